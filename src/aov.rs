@@ -0,0 +1,139 @@
+// Arbitrary output variables: properties of the first surface seen through each pixel, written alongside the main
+// image for compositing and denoising
+
+use std::io;
+use std::str::FromStr;
+
+use crate::exr::{write_exr, Channel};
+use crate::film::Film;
+use crate::hittable::{HitRecord, Ray};
+use crate::vec3::Vec3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aov {
+    // Distance along the camera ray rather than the hit parameter t, which depends on the length of the ray direction
+    // and so varies across the image. Its layer is named distance to tell it apart from t.
+    Depth,
+    Normal,
+    ShadingNormal,
+    Albedo,
+    Position,
+    Uv,
+    MaterialId,
+    ObjectId,
+}
+
+impl FromStr for Aov {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Aov, String> {
+        match s {
+            "depth" | "distance" => Ok(Aov::Depth),
+            "normal" => Ok(Aov::Normal),
+            "shading-normal" => Ok(Aov::ShadingNormal),
+            "albedo" => Ok(Aov::Albedo),
+            "position" => Ok(Aov::Position),
+            "uv" => Ok(Aov::Uv),
+            "material-id" => Ok(Aov::MaterialId),
+            "object-id" => Ok(Aov::ObjectId),
+            _ => Err(format!("Unknown AOV {s}")),
+        }
+    }
+}
+
+impl Aov {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Depth => "distance",
+            Aov::Normal => "normal",
+            Aov::ShadingNormal => "shading_normal",
+            Aov::Albedo => "albedo",
+            Aov::Position => "position",
+            Aov::Uv => "uv",
+            Aov::MaterialId => "material_id",
+            Aov::ObjectId => "object_id",
+        }
+    }
+    fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Depth => &["Z"],
+            Aov::Normal | Aov::ShadingNormal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Albedo => &["R", "G", "B"],
+            Aov::Uv => &["U", "V"],
+            Aov::MaterialId | Aov::ObjectId => &["id"],
+        }
+    }
+    // IDs can not be averaged, so a pixel keeps the ID seen by its first sample
+    pub fn is_id(&self) -> bool {
+        matches!(self, Aov::MaterialId | Aov::ObjectId)
+    }
+
+    // value for a camera ray and its first hit, zero where the ray leaves the scene
+    pub fn value(&self, ray: &Ray, rec: Option<&HitRecord>) -> Vec3 {
+        let Some(rec) = rec else {
+            return Vec3::zero();
+        };
+        match self {
+            Aov::Depth => Vec3::one() * rec.t * ray.direction.length(),
+            Aov::Normal => rec.geometric_normal,
+            Aov::ShadingNormal => rec.material.shading_normal(rec),
+            Aov::Albedo => rec.material.albedo(rec),
+            Aov::Position => rec.p,
+            Aov::Uv => Vec3(rec.uv.0, rec.uv.1, 0.0),
+            Aov::MaterialId => Vec3::one() * rec.material_id as f32,
+            Aov::ObjectId => Vec3::one() * rec.object_id as f32,
+        }
+    }
+}
+
+// channels of the k-th AOV of the film, named layer.channel when a layer is given
+fn aov_channels(film: &Film, k: usize, layer: Option<&str>) -> Vec<Channel> {
+    let aov = film.aovs()[k];
+    aov.channels()
+        .iter()
+        .enumerate()
+        .map(|(c, name)| Channel {
+            name: match layer {
+                Some(layer) => format!("{layer}.{name}"),
+                None => String::from(*name),
+            },
+            values: (0..film.height)
+                .flat_map(|j| (0..film.width).map(move |i| (i, j)))
+                .map(|(i, j)| film.aov_pixel(k, i, j)[c])
+                .collect(),
+        })
+        .collect()
+}
+
+// the main image as R, G and B with one layer per AOV
+pub fn write_multilayer(path: &str, film: &Film) -> io::Result<()> {
+    let mut channels: Vec<Channel> = ["R", "G", "B"]
+        .iter()
+        .enumerate()
+        .map(|(c, name)| Channel {
+            name: String::from(*name),
+            values: (0..film.height)
+                .flat_map(|j| (0..film.width).map(move |i| (i, j)))
+                .map(|(i, j)| film.pixel(i, j)[c])
+                .collect(),
+        })
+        .collect();
+    for (k, aov) in film.aovs().iter().enumerate() {
+        channels.extend(aov_channels(film, k, Some(aov.name())));
+    }
+    write_exr(path, film.width, film.height, &mut channels)
+}
+
+// one file per AOV, named stem.aov.exr
+pub fn write_separate(stem: &str, film: &Film) -> io::Result<()> {
+    for (k, aov) in film.aovs().iter().enumerate() {
+        let mut channels = aov_channels(film, k, None);
+        write_exr(
+            &format!("{stem}.{}.exr", aov.name()),
+            film.width,
+            film.height,
+            &mut channels,
+        )?;
+    }
+    Ok(())
+}
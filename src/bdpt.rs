@@ -0,0 +1,513 @@
+// Bidirectional path tracing (Veach 1997). Each sample traces a subpath from the camera and one from a light, and
+// connects every prefix of one to every prefix of the other. Every connection is another strategy for sampling the
+// same kind of path, and their estimates are combined with the power heuristic over all the strategies that could
+// have sampled the path. Connecting light subpaths to the camera itself (light tracing) reaches the film elsewhere
+// than the pixel being sampled, and is splatted.
+
+use std::f32::consts::PI;
+
+use crate::camera::Camera;
+use crate::hittable::{HitRecord, Hittable, Ray};
+use crate::integrator::{is_specular, russian_roulette, transmittance, Integrator, Radiance, Splat};
+use crate::sampler::Sampler;
+use crate::scene::{Light, Scene};
+use crate::vec3::Vec3;
+
+enum Kind {
+    Camera,
+    Light, // where a light subpath starts, or a point sampled on a light
+    Surface(HitRecord),
+    Sky, // a camera subpath left the scene, or a light subpath starts from the sky
+}
+
+// Densities are per unit area at the vertex, or per unit solid angle for the sky. Light subpaths from the sky start
+// with parallel rays from a disk, at a density per unit area across the rays. Specular scatter has no density,
+// it is counted as 1 in both directions as every strategy that can sample the path has exactly one of them.
+struct Vertex {
+    kind: Kind,
+    p: Vec3,
+    normal: Vec3,   // geometric normal, zero at the camera
+    incoming: Vec3, // unit direction the subpath arrived in, or left the scene in for the sky
+    beta: Vec3,     // throughput of the subpath up to the vertex
+    delta: bool,    // the subpath scattered specularly here, or the material can not be evaluated
+    pdf_fwd: f32,   // of the vertex being sampled by its own subpath
+    pdf_rev: f32,   // of the vertex being sampled by the other subpath, once it is known
+}
+
+impl Vertex {
+    fn new(kind: Kind, p: Vec3, normal: Vec3, beta: Vec3, pdf_fwd: f32) -> Vertex {
+        Vertex {
+            kind,
+            p,
+            normal,
+            incoming: Vec3::zero(),
+            beta,
+            delta: false,
+            pdf_fwd,
+            pdf_rev: 0.0,
+        }
+    }
+    fn rec(&self) -> Option<&HitRecord> {
+        match &self.kind {
+            Kind::Surface(rec) => Some(rec),
+            _ => None,
+        }
+    }
+    fn direction_to(&self, to: &Vertex) -> Vec3 {
+        match (&self.kind, &to.kind) {
+            (_, Kind::Sky) => to.incoming,
+            (Kind::Sky, _) => -self.incoming,
+            _ => (to.p - self.p).normalize(),
+        }
+    }
+    // converts a solid angle density at this vertex, or the density across the rays of the sky, to an area density at
+    // `to`
+    fn to_area(&self, pdf: f32, to: &Vertex) -> f32 {
+        match (&self.kind, &to.kind) {
+            (_, Kind::Sky) => pdf,
+            (Kind::Sky, _) => pdf * to.normal.dot(self.incoming).abs(),
+            _ => {
+                let d = to.p - self.p;
+                pdf * to.normal.dot(d).abs() / (d.length2() * d.length())
+            }
+        }
+    }
+    // BSDF times cosine, in the colour space of ray, and the solid angle pdf of scatter, arrived at along incoming
+    fn eval(&self, ray: &Ray, incoming: Vec3, wi: Vec3) -> Option<(Vec3, f32)> {
+        let rec = self.rec()?;
+        let arriving = Ray { origin: self.p, direction: incoming, wavelengths: ray.wavelengths };
+        let (f, pdf) = rec.material.eval(&arriving, rec, wi)?;
+        Some((arriving.spectrum(f), pdf))
+    }
+    // area density at `to` of scattering towards it, arrived at along incoming
+    fn scatter_pdf(&self, ray: &Ray, incoming: Vec3, to: &Vertex) -> f32 {
+        match self.eval(ray, incoming, self.direction_to(to)) {
+            Some((_, pdf)) => self.to_area(pdf, to),
+            None => 0.0,
+        }
+    }
+    // area density at `to` of the light at this vertex emitting towards it
+    fn emission_pdf(&self, scene: &Scene, to: &Vertex) -> f32 {
+        match (&self.kind, scene.emitter(Some(self.p))) {
+            (Kind::Sky, _) => {
+                let (_, radius) = scene.bounding_sphere();
+                self.to_area(1.0 / (PI * radius * radius), to)
+            }
+            (_, None) => 0.0,
+            (_, Some(light)) => self.to_area(light.emission_pdf(self.normal, self.direction_to(to)), to),
+        }
+    }
+}
+
+// whether nothing blocks the way from a surface to a point at distance along direction
+fn unoccluded(scene: &Scene, ray: &Ray, rec: &HitRecord, direction: Vec3, distance: f32) -> bool {
+    let shadow = ray.spawn(rec, direction);
+    transmittance(scene, shadow, distance * (1.0 - 1e-3), None).0 > 0.0
+}
+
+pub struct BdptIntegrator {
+    max_depth: u32,
+    min_depth: u32,
+}
+
+impl BdptIntegrator {
+    pub fn new(max_depth: u32, min_depth: u32) -> BdptIntegrator {
+        BdptIntegrator { max_depth, min_depth }
+    }
+
+    // Extends the subpath from its last vertex along ray, whose direction was sampled with solid angle density pdf,
+    // until it leaves the scene, is absorbed or has max_vertices. Camera subpaths end with a sky vertex when they
+    // leave the scene. Returns the last ray of the subpath.
+    #[allow(clippy::too_many_arguments)]
+    fn walk(
+        &self,
+        scene: &Scene,
+        mut ray: Ray,
+        mut beta: Vec3,
+        mut pdf: f32,
+        max_vertices: usize,
+        sampler: &mut dyn Sampler,
+        path: &mut Vec<Vertex>,
+        from_camera: bool,
+    ) -> Ray {
+        while path.len() < max_vertices {
+            let incoming = ray.direction.normalize();
+            let prev = path.len() - 1;
+            let Some(rec) = scene.objects.hit(&ray, 0.001, f32::INFINITY) else {
+                if from_camera {
+                    let mut sky = Vertex::new(Kind::Sky, ray.origin, Vec3::zero(), beta, pdf);
+                    sky.incoming = incoming;
+                    path.push(sky);
+                }
+                break;
+            };
+            let mut vertex = Vertex::new(Kind::Surface(rec.clone()), rec.p, rec.geometric_normal, beta, 0.0);
+            vertex.pdf_fwd = match path[prev].delta {
+                true => pdf,
+                false => path[prev].to_area(pdf, &vertex),
+            };
+            vertex.incoming = incoming;
+            vertex.delta = is_specular(&ray, &rec);
+            path.push(vertex);
+
+            let Some((direction, color)) = rec.material.scatter(&ray, &rec, sampler) else {
+                break;
+            };
+            let wi = direction.normalize();
+            let pdfs = match path[prev + 1].delta {
+                true => None,
+                false => {
+                    let fwd = path[prev + 1].eval(&ray, incoming, wi).map_or(0.0, |(_, pdf)| pdf);
+                    let rev = path[prev + 1].scatter_pdf(&ray, -wi, &path[prev]);
+                    Some((fwd, rev)).filter(|&(fwd, _)| fwd > 0.0)
+                }
+            };
+            // specular scatter, including delta lobes of materials that can be evaluated otherwise
+            let (fwd, rev) = pdfs.unwrap_or((1.0, 1.0));
+            path[prev + 1].delta = pdfs.is_none();
+            path[prev].pdf_rev = rev;
+
+            beta = beta * ray.spectrum(color);
+            if path.len() > self.min_depth as usize && !russian_roulette(&mut beta, sampler) {
+                break;
+            }
+            pdf = fwd;
+            ray = ray.spawn(&rec, direction);
+        }
+        ray
+    }
+
+    // the vertices of a subpath from a light, and its last ray
+    fn light_subpath(&self, scene: &Scene, ray: &Ray, sampler: &mut dyn Sampler) -> (Vec<Vertex>, Option<Ray>) {
+        let mut path = Vec::new();
+        let Some((light, choice_pdf)) = scene.sample_light(sampler) else {
+            return (path, None);
+        };
+        // The sky starts from a disk as wide as the scene. Its start is a direction, so its own density is the
+        // direction's, and the disk's density is that of the next vertex.
+        let sky = matches!(light, Light::Sky { .. });
+        let emission = match sky {
+            true => light.sample_sky_emission(scene.bounding_sphere(), sampler),
+            false => light.sample_emission(sampler),
+        };
+        let Some(emission) = emission else {
+            return (path, None);
+        };
+        let pdf_origin = emission.pdf_position * choice_pdf;
+        let radiance = ray.spectrum(emission.radiance);
+        let beta = radiance / pdf_origin;
+        path.push(match sky {
+            true => Vertex {
+                incoming: -emission.direction,
+                ..Vertex::new(
+                    Kind::Sky,
+                    emission.point,
+                    Vec3::zero(),
+                    beta,
+                    emission.pdf_direction * choice_pdf,
+                )
+            },
+            false => Vertex::new(Kind::Light, emission.point, emission.normal, beta, pdf_origin),
+        });
+
+        let cos_theta = emission.direction.dot(emission.normal);
+        let beta = radiance * (cos_theta / (pdf_origin * emission.pdf_direction));
+        let origin = emission.point + emission.normal * 1e-4;
+        let light_ray = Ray { origin, direction: emission.direction, wavelengths: ray.wavelengths };
+        let max_vertices = self.max_depth as usize + 1;
+        let pdf = match sky {
+            true => emission.pdf_position,
+            false => emission.pdf_direction,
+        };
+        let last = self.walk(scene, light_ray, beta, pdf, max_vertices, sampler, &mut path, false);
+        (path, Some(last))
+    }
+
+    // Weight of the strategy with t camera and s light vertices among all that could sample the path. A vertex
+    // sampled for the connection instead of taken from its subpath (the lens point for t = 1, the light point for
+    // s = 1) is given as sampled.
+    #[allow(clippy::too_many_arguments)]
+    fn mis_weight(
+        &self,
+        scene: &Scene,
+        camera: &dyn Camera,
+        ray: &Ray,
+        camera_path: &[Vertex],
+        light_path: &[Vertex],
+        sampled: Option<&Vertex>,
+        s: usize,
+        t: usize,
+    ) -> f32 {
+        // the path without the camera vertex, from the camera side: vertex i is path[i - 1]
+        let mut path: Vec<&Vertex> = camera_path[1..t].iter().collect();
+        match s {
+            1 => path.push(sampled.unwrap()),
+            _ => path.extend(light_path[..s].iter().rev()),
+        }
+        let camera_vertex = match t {
+            1 => sampled.unwrap(),
+            _ => &camera_path[0],
+        };
+        let k = path.len();
+        let vertex = |i: usize| match i {
+            0 => camera_vertex,
+            _ => path[i - 1],
+        };
+
+        let mut fwd = vec![0.0; k + 1];
+        let mut rev = vec![0.0; k + 1];
+        let mut delta = vec![false; k + 1];
+        for i in 1..=k {
+            (fwd[i], rev[i], delta[i]) = match i < t {
+                true => (vertex(i).pdf_fwd, vertex(i).pdf_rev, vertex(i).delta),
+                false => (vertex(i).pdf_rev, vertex(i).pdf_fwd, vertex(i).delta),
+            };
+        }
+
+        // densities at and next to the connection, which the subpaths did not know
+        match (s, t) {
+            (0, _) => {
+                if k >= 2 {
+                    rev[k - 1] = vertex(k).emission_pdf(scene, vertex(k - 1));
+                }
+            }
+            (1, _) => {
+                let (x, light) = (vertex(k - 1), vertex(k));
+                fwd[k] = x.scatter_pdf(ray, x.incoming, light);
+                rev[k - 1] = light.emission_pdf(scene, x);
+                if k >= 3 {
+                    rev[k - 2] = x.scatter_pdf(ray, -x.direction_to(light), vertex(k - 2));
+                }
+                delta[k - 1] = false;
+            }
+            (_, 1) => {
+                let (lens, y) = (vertex(0), vertex(1));
+                let direction_pdf = camera.direction_pdf(&Ray::new(lens.p, y.p - lens.p)).unwrap_or(0.0);
+                fwd[1] = lens.to_area(direction_pdf, y);
+                fwd[2] = y.scatter_pdf(ray, lens.direction_to(y), vertex(2));
+                delta[1] = false;
+            }
+            _ => {
+                let (x, y) = (vertex(t - 1), vertex(t));
+                rev[t - 1] = y.scatter_pdf(ray, y.incoming, x);
+                if t >= 3 {
+                    rev[t - 2] = x.scatter_pdf(ray, -x.direction_to(y), vertex(t - 2));
+                }
+                fwd[t] = x.scatter_pdf(ray, x.incoming, y);
+                fwd[t + 1] = y.scatter_pdf(ray, x.direction_to(y), vertex(t + 1));
+                (delta[t - 1], delta[t]) = (false, false);
+            }
+        }
+
+        // The light end is sampled as the start of a light subpath, or by light sampling from vertex k - 1
+        let light = vertex(k);
+        let pdf_origin = match light.kind {
+            Kind::Sky => scene.origin_pdf(None),
+            _ => scene.origin_pdf(Some(light.p)),
+        };
+        let pdf_light = match light.kind {
+            Kind::Sky => scene.light_pdf(vertex(k - 1).p, light.incoming, None),
+            _ => {
+                let direction = vertex(k - 1).direction_to(light);
+                vertex(k - 1).to_area(scene.light_pdf(vertex(k - 1).p, direction, Some(light.p)), light)
+            }
+        };
+        let splats = camera.direction_pdf(ray).is_some();
+
+        // log density of sampling the path with t' camera vertices
+        let ln = |pdf: f32| (pdf as f64).ln();
+        let log_pdf = |t2: usize| -> Option<f64> {
+            let s2 = k + 1 - t2;
+            let valid = match s2 {
+                0 => true,
+                1 => t2 >= 2 && !delta[k - 1] && pdf_light > 0.0,
+                _ => pdf_origin > 0.0 && !delta[t2] && if t2 == 1 { splats } else { !delta[t2 - 1] },
+            };
+            if !valid {
+                return None;
+            }
+            let end = match s2 {
+                0 => 0.0,
+                1 => ln(pdf_light),
+                _ => ln(pdf_origin),
+            };
+            let camera_side: f64 = (1..t2).map(|i| ln(fwd[i])).sum();
+            let light_side: f64 = (t2..k).map(|i| ln(rev[i])).sum();
+            Some(camera_side + light_side + end)
+        };
+        let Some(current) = log_pdf(t).filter(|l| l.is_finite()) else {
+            return 0.0;
+        };
+        let sum: f64 = (1..=k + 1)
+            .filter_map(log_pdf)
+            .map(|l| (2.0 * (l - current)).exp())
+            .sum();
+        (1.0 / sum) as f32
+    }
+
+    // weighted estimate of the strategy with s light and t >= 2 camera vertices
+    #[allow(clippy::too_many_arguments)]
+    fn connect(
+        &self,
+        scene: &Scene,
+        camera: &dyn Camera,
+        ray: &Ray,
+        camera_path: &[Vertex],
+        light_path: &[Vertex],
+        s: usize,
+        t: usize,
+        sampler: &mut dyn Sampler,
+    ) -> Vec3 {
+        let x = &camera_path[t - 1];
+        let mut sampled = None;
+        let value = match s {
+            // the camera subpath found a light
+            0 => match &x.kind {
+                Kind::Sky => x.beta * ray.spectrum(scene.background(&Ray::new(x.p, x.incoming))),
+                Kind::Surface(rec) => {
+                    let arriving = Ray::new(x.p - x.incoming, x.incoming);
+                    x.beta * ray.spectrum(rec.material.emitted(&arriving, rec))
+                }
+                _ => Vec3::zero(),
+            },
+            // light sampling
+            1 => {
+                let Some(rec) = x.rec() else {
+                    return Vec3::zero();
+                };
+                let Some((light, choice_pdf)) = scene.sample_light(sampler) else {
+                    return Vec3::zero();
+                };
+                let Some(sample) = light.sample(x.p, sampler) else {
+                    return Vec3::zero();
+                };
+                let Some((f, _)) = x.eval(ray, x.incoming, sample.direction) else {
+                    return Vec3::zero();
+                };
+                if !unoccluded(scene, ray, rec, sample.direction, sample.distance) {
+                    return Vec3::zero();
+                }
+                let mut vertex = match *light {
+                    Light::Sky { .. } => Vertex::new(Kind::Sky, x.p, Vec3::zero(), Vec3::one(), 0.0),
+                    Light::Sphere { center, .. } => {
+                        let p = x.p + sample.direction * sample.distance;
+                        Vertex::new(Kind::Light, p, (p - center).normalize(), Vec3::one(), 0.0)
+                    }
+                };
+                vertex.incoming = sample.direction;
+                sampled = Some(vertex);
+                x.beta * f * ray.spectrum(sample.radiance) / (sample.pdf * choice_pdf)
+            }
+            _ => {
+                let y = &light_path[s - 1];
+                let (Some(rec), Some(_)) = (x.rec(), y.rec()) else {
+                    return Vec3::zero();
+                };
+                let d = y.p - x.p;
+                let distance = d.length();
+                let direction = d / distance;
+                let (Some((fx, _)), Some((fy, _))) =
+                    (x.eval(ray, x.incoming, direction), y.eval(ray, y.incoming, -direction))
+                else {
+                    return Vec3::zero();
+                };
+                if !unoccluded(scene, ray, rec, direction, distance) {
+                    return Vec3::zero();
+                }
+                x.beta * fx * fy * y.beta / (distance * distance)
+            }
+        };
+        if value.0.max(value.1).max(value.2) <= 0.0 {
+            return Vec3::zero();
+        }
+        value * self.mis_weight(scene, camera, ray, camera_path, light_path, sampled.as_ref(), s, t)
+    }
+
+    // light tracing: the light subpath with s vertices connected to a point sampled on the lens
+    #[allow(clippy::too_many_arguments)]
+    fn connect_to_camera(
+        &self,
+        scene: &Scene,
+        camera: &dyn Camera,
+        ray: &Ray,
+        camera_path: &[Vertex],
+        light_path: &[Vertex],
+        s: usize,
+        sampler: &mut dyn Sampler,
+    ) -> Option<Splat> {
+        let y = &light_path[s - 1];
+        let rec = y.rec()?;
+        let importance = camera.sample_importance(y.p, sampler)?;
+        let d = importance.origin - y.p;
+        let distance = d.length();
+        let direction = d / distance;
+        let (f, _) = y.eval(ray, y.incoming, direction)?;
+        if !unoccluded(scene, ray, rec, direction, distance) {
+            return None;
+        }
+        let value = y.beta * f * importance.weight;
+        if value.0.max(value.1).max(value.2) <= 0.0 {
+            return None;
+        }
+        let lens = Vertex::new(Kind::Camera, importance.origin, Vec3::zero(), Vec3::one(), 1.0);
+        let weight = self.mis_weight(scene, camera, ray, camera_path, light_path, Some(&lens), s, 1);
+        Some(Splat {
+            s: importance.s,
+            t: importance.t,
+            radiance: value * weight,
+            bounces: (s - 1) as u32,
+        })
+    }
+}
+
+impl Integrator for BdptIntegrator {
+    fn li(&self, ray: Ray, scene: &Scene, camera: &dyn Camera, sampler: &mut dyn Sampler) -> Radiance {
+        let mut camera_path = vec![Vertex::new(Kind::Camera, ray.origin, Vec3::zero(), Vec3::one(), 1.0)];
+        let pdf = camera.direction_pdf(&ray).unwrap_or(1.0);
+        let first = Ray { origin: ray.origin, direction: ray.direction, wavelengths: ray.wavelengths };
+        let max_vertices = self.max_depth as usize + 2;
+        let camera_last = self.walk(
+            scene,
+            first,
+            Vec3::one(),
+            pdf,
+            max_vertices,
+            sampler,
+            &mut camera_path,
+            true,
+        );
+        let (light_path, light_last) = self.light_subpath(scene, &ray, sampler);
+
+        let mut radiance = Radiance::zero();
+        // every strategy of the sample is converted alike, with the hero wavelength alone after dispersion on
+        // either subpath
+        radiance.end_path(&camera_last);
+        if let Some(light_last) = &light_last {
+            radiance.end_path(light_last);
+        }
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                let depth = s + t;
+                if depth < 2 || depth - 2 > self.max_depth as usize || (s, t) == (1, 1) {
+                    continue;
+                }
+                match t {
+                    1 => radiance.splats.extend(self.connect_to_camera(
+                        scene,
+                        camera,
+                        &ray,
+                        &camera_path,
+                        &light_path,
+                        s,
+                        sampler,
+                    )),
+                    _ => {
+                        let value = self.connect(scene, camera, &ray, &camera_path, &light_path, s, t, sampler);
+                        radiance.add((depth - 2) as u32, value);
+                    }
+                }
+            }
+        }
+        radiance
+    }
+}
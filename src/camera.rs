@@ -1,9 +1,123 @@
-use crate::hittable::{Hittable, HittableList, Ray};
-use crate::pcg32::PCG32;
+use crate::checkpoint::Checkpoint;
+use crate::film::{CropWindow, Film};
+use crate::hittable::Ray;
+use crate::image::Image;
+use crate::integrator::{first_surface, Integrator, Radiance, Splat};
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::spectrum::Wavelengths;
 use crate::vec3::Vec3;
 use std::f32::consts::PI;
+use std::time::{Duration, Instant};
 
-pub struct Camera {
+pub trait Camera {
+    // s and t are film coordinates in [0, 1], from the left and from the bottom. Returns the ray and its weight, which
+    // is less than 1 where the lens darkens the image. None if no ray leaves the camera.
+    fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<(Ray, f32)>;
+    // For light tracing: a point sampled on the lens that sees p, the film coordinates of the ray from it through p,
+    // and the importance arriving at p divided by the density of the lens point. None where p is outside the image, or
+    // for cameras that do not support it.
+    fn sample_importance(&self, _p: Vec3, _sampler: &mut dyn Sampler) -> Option<ImportanceSample> {
+        None
+    }
+    // solid angle pdf of get_ray producing the direction of a camera ray, None where sample_importance is not supported
+    fn direction_pdf(&self, _ray: &Ray) -> Option<f32> {
+        None
+    }
+    // f-number for physical exposure, assuming scene units are metres. None for cameras without a finite aperture.
+    fn f_number(&self) -> Option<f32> {
+        None
+    }
+}
+
+pub struct ImportanceSample {
+    pub origin: Vec3,
+    pub s: f32,
+    pub t: f32,
+    pub weight: f32,
+}
+
+// orthonormal basis of a camera at look_from looking at look_at. NOTE: camera pointing in negative z direction
+fn look_at_basis(look_from: Vec3, look_at: Vec3, vup: Vec3) -> (Vec3, Vec3, Vec3) {
+    let w = (look_from - look_at).normalize();
+    let u = vup.cross(w).normalize();
+    let v = w.cross(u);
+    (u, v, w)
+}
+
+// Shape of the lens aperture, which is also the shape of out of focus highlights (bokeh)
+pub enum ApertureShape {
+    Circle,
+    Polygon { blades: u32, rotation: f32 }, // rotation in degrees
+    Image { image: Image, cdf: Vec<f32> },  // brightness of the image is the transmission of the aperture
+}
+impl ApertureShape {
+    pub fn image(image: Image) -> ApertureShape {
+        let mut total = 0.0;
+        let mut cdf: Vec<f32> = image
+            .pixels
+            .iter()
+            .map(|pixel| {
+                total += pixel.luminance().max(0.0);
+                total
+            })
+            .collect();
+        assert!(total > 0.0, "Aperture image is completely black");
+        cdf.iter_mut().for_each(|c| *c /= total);
+        ApertureShape::Image { image, cdf }
+    }
+
+    // point on the aperture, within the unit disk or the unit square for images
+    fn sample(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        match self {
+            ApertureShape::Circle => Vec3::random_unit_disk(sampler),
+            ApertureShape::Polygon { blades, rotation } => {
+                // pick one of the triangles fanning out from the centre, then a uniform point in it
+                let step = 2.0 * PI / *blades as f32;
+                let blade = ((sampler.get_1d() * *blades as f32) as u32).min(blades - 1);
+                let angle = blade as f32 * step + rotation * PI / 180.0;
+                let a = Vec3(angle.cos(), angle.sin(), 0.0);
+                let b = Vec3((angle + step).cos(), (angle + step).sin(), 0.0);
+                let (mut u1, mut u2) = sampler.get_2d();
+                if u1 + u2 > 1.0 {
+                    (u1, u2) = (1.0 - u1, 1.0 - u2);
+                }
+                a * u1 + b * u2
+            }
+            ApertureShape::Image { image, cdf } => {
+                let u = sampler.get_1d();
+                let index = cdf.partition_point(|&c| c <= u).min(cdf.len() - 1) as u32;
+                let (dx, dy) = sampler.get_2d();
+                let x = (index % image.width) as f32 + dx;
+                let y = (index / image.width) as f32 + dy;
+                Vec3(
+                    2.0 * x / image.width as f32 - 1.0,
+                    1.0 - 2.0 * y / image.height as f32,
+                    0.0,
+                )
+            }
+        }
+    }
+}
+
+// Photographic exposure. Film radiance is scaled so that a scene in cd/m^2 is exposed like a real camera would,
+// using the saturation based sensitivity convention (ISO 12232).
+pub struct Exposure {
+    pub shutter: f32, // seconds
+    pub f_number: f32,
+    pub iso: f32,
+}
+impl Exposure {
+    pub fn ev100(&self) -> f32 {
+        (self.f_number * self.f_number / self.shutter * 100.0 / self.iso).log2()
+    }
+    pub fn scale(&self) -> f32 {
+        1.0 / (1.2 * self.ev100().exp2())
+    }
+}
+
+// thin lens perspective camera
+pub struct PerspectiveCamera {
     origin: Vec3,
     lower_left_corner: Vec3,
     horizontal: Vec3,
@@ -12,9 +126,13 @@ pub struct Camera {
     v: Vec3,
     w: Vec3,
     lens_radius: f32,
+    focus_distance: f32,
+    aperture_shape: ApertureShape,
+    cos4_falloff: bool,
+    optical_vignetting: f32,
 }
 
-impl Camera {
+impl PerspectiveCamera {
     pub fn new(
         aspect_ratio: f32,
         look_from: Vec3,
@@ -23,23 +141,19 @@ impl Camera {
         vfov: f32,
         aperture: f32,
         focus_distance: f32,
-    ) -> Camera {
+    ) -> PerspectiveCamera {
         let theta = vfov * PI / 180.0;
         let h = (theta / 2.0).tan();
         let viewport_height = 2.0 * h;
         let viewport_width = viewport_height * aspect_ratio;
 
-        let w = (look_from - look_at).normalize();
-        let u = vup.cross(w).normalize();
-        let v = w.cross(u);
-
-        // NOTE: camera pointing in negative z direction
+        let (u, v, w) = look_at_basis(look_from, look_at, vup);
         let origin = look_from;
         let horizontal = focus_distance * viewport_width * u;
         let vertical = focus_distance * viewport_height * v;
         let lower_left_corner = origin - 0.5 * horizontal - 0.5 * vertical - focus_distance * w;
 
-        Camera {
+        PerspectiveCamera {
             origin,
             lower_left_corner,
             horizontal,
@@ -48,15 +162,259 @@ impl Camera {
             v,
             w,
             lens_radius: aperture / 2.0,
+            focus_distance,
+            aperture_shape: ApertureShape::Circle,
+            cos4_falloff: false,
+            optical_vignetting: 0.0,
         }
     }
-    fn get_ray(&self, s: f32, t: f32, rng: &mut PCG32) -> Ray {
-        let rd = self.lens_radius * Vec3::random_unit_disk(rng);
+    // cos4_falloff darkens the image by cos^4 of the angle to the optical axis (natural vignetting). With optical
+    // vignetting > 0, the lens barrel clips the aperture towards the image corners, 1 clips the corners completely.
+    pub fn with_vignetting(mut self, cos4_falloff: bool, optical_vignetting: f32) -> PerspectiveCamera {
+        self.cos4_falloff = cos4_falloff;
+        self.optical_vignetting = optical_vignetting;
+        self
+    }
+    pub fn with_aperture_shape(mut self, aperture_shape: ApertureShape) -> PerspectiveCamera {
+        self.aperture_shape = aperture_shape;
+        self
+    }
+    // Light tracing needs the density of lens points, known for uniformly sampled apertures only, and no vignetting
+    // by the lens barrel
+    fn supports_importance(&self) -> bool {
+        !matches!(self.aperture_shape, ApertureShape::Image { .. }) && self.optical_vignetting == 0.0
+    }
+    // area of the film on a plane at distance 1 in front of the lens
+    fn film_area(&self) -> f32 {
+        self.horizontal.length() * self.vertical.length() / (self.focus_distance * self.focus_distance)
+    }
+}
+impl Camera for PerspectiveCamera {
+    fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<(Ray, f32)> {
+        let lens = self.aperture_shape.sample(sampler);
+        // the lens barrel acts as a second circular stop shifted towards the image corners, giving cat-eye bokeh
+        let (x, y) = (2.0 * s - 1.0, 2.0 * t - 1.0);
+        if self.optical_vignetting > 0.0 {
+            let (dx, dy) = (
+                lens.0 - self.optical_vignetting * x,
+                lens.1 - self.optical_vignetting * y,
+            );
+            if dx * dx + dy * dy > 1.0 {
+                return None;
+            }
+        }
+        let rd = self.lens_radius * lens;
         let offset = self.u * rd.0 + self.v * rd.1;
-        Ray::new(
-            self.origin + offset,
-            self.lower_left_corner + self.horizontal * s + self.vertical * t - self.origin - offset,
-        )
+        let direction = self.lower_left_corner + self.horizontal * s + self.vertical * t - self.origin - offset;
+        let weight = match self.cos4_falloff {
+            true => (-direction.normalize().dot(self.w)).powi(4),
+            false => 1.0,
+        };
+        Some((Ray::new(self.origin + offset, direction), weight))
+    }
+    // Importance is 1 / (film area * lens area * cos^4) per unit solid angle and lens area, so that light tracing
+    // agrees with camera rays. Over a lens point sampled with density 1 / lens area and seen from p at distance d,
+    // that is 1 / (film area * cos^3 * d^2). A pinhole counts as a lens of area 1.
+    fn sample_importance(&self, p: Vec3, sampler: &mut dyn Sampler) -> Option<ImportanceSample> {
+        if !self.supports_importance() {
+            return None;
+        }
+        let rd = self.lens_radius * self.aperture_shape.sample(sampler);
+        let origin = self.origin + self.u * rd.0 + self.v * rd.1;
+        let direction = p - origin;
+        let distance2 = direction.length2();
+        let cos_theta = direction.dot(-self.w) / distance2.sqrt();
+        if cos_theta <= 0.0 {
+            return None;
+        }
+        // where the ray crosses the plane in focus, in film coordinates
+        let focus = origin + direction * (self.focus_distance / direction.dot(-self.w)) - self.lower_left_corner;
+        let s = focus.dot(self.horizontal) / self.horizontal.length2();
+        let t = focus.dot(self.vertical) / self.vertical.length2();
+        if !(0.0..1.0).contains(&s) || !(0.0..1.0).contains(&t) {
+            return None;
+        }
+        let falloff = match self.cos4_falloff {
+            true => cos_theta.powi(4),
+            false => 1.0,
+        };
+        let weight = falloff / (self.film_area() * cos_theta.powi(3) * distance2);
+        Some(ImportanceSample { origin, s, t, weight })
+    }
+    fn direction_pdf(&self, ray: &Ray) -> Option<f32> {
+        if !self.supports_importance() {
+            return None;
+        }
+        let cos_theta = ray.direction.normalize().dot(-self.w);
+        match cos_theta > 0.0 {
+            true => Some(1.0 / (self.film_area() * cos_theta.powi(3))),
+            false => Some(0.0),
+        }
+    }
+    // for a full frame (24 mm high) sensor with the same field of view
+    fn f_number(&self) -> Option<f32> {
+        let focal_length = 0.024 * self.focus_distance / self.vertical.length();
+        (self.lens_radius > 0.0).then(|| focal_length / (2.0 * self.lens_radius))
+    }
+}
+
+// parallel rays, view_height is the height of the film in world units
+pub struct OrthographicCamera {
+    lower_left_corner: Vec3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    direction: Vec3,
+}
+impl OrthographicCamera {
+    pub fn new(aspect_ratio: f32, look_from: Vec3, look_at: Vec3, vup: Vec3, view_height: f32) -> OrthographicCamera {
+        let (u, v, w) = look_at_basis(look_from, look_at, vup);
+        let horizontal = view_height * aspect_ratio * u;
+        let vertical = view_height * v;
+        OrthographicCamera {
+            lower_left_corner: look_from - 0.5 * horizontal - 0.5 * vertical,
+            horizontal,
+            vertical,
+            direction: -w,
+        }
+    }
+}
+impl Camera for OrthographicCamera {
+    fn get_ray(&self, s: f32, t: f32, _sampler: &mut dyn Sampler) -> Option<(Ray, f32)> {
+        let origin = self.lower_left_corner + self.horizontal * s + self.vertical * t;
+        Some((Ray::new(origin, self.direction), 1.0))
+    }
+}
+
+// Equidistant fisheye: the angle from the optical axis is proportional to the distance from the image centre.
+// The image circle fits the film height, outside of it there are no rays.
+pub struct FisheyeCamera {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    aspect_ratio: f32,
+    half_fov: f32,
+}
+impl FisheyeCamera {
+    pub fn new(aspect_ratio: f32, look_from: Vec3, look_at: Vec3, vup: Vec3, fov: f32) -> FisheyeCamera {
+        let (u, v, w) = look_at_basis(look_from, look_at, vup);
+        let half_fov = fov * PI / 360.0;
+        FisheyeCamera { origin: look_from, u, v, w, aspect_ratio, half_fov }
+    }
+}
+impl Camera for FisheyeCamera {
+    fn get_ray(&self, s: f32, t: f32, _sampler: &mut dyn Sampler) -> Option<(Ray, f32)> {
+        let x = (2.0 * s - 1.0) * self.aspect_ratio;
+        let y = 2.0 * t - 1.0;
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+        let theta = r * self.half_fov;
+        let phi = f32::atan2(y, x);
+        let direction = theta.sin() * (phi.cos() * self.u + phi.sin() * self.v) - theta.cos() * self.w;
+        Some((Ray::new(self.origin, direction), 1.0))
+    }
+}
+
+// Cylindrical panorama: linear in angle horizontally, perspective vertically
+pub struct PanoramaCamera {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    hfov: f32,
+    viewport_height: f32,
+}
+impl PanoramaCamera {
+    pub fn new(look_from: Vec3, look_at: Vec3, vup: Vec3, hfov: f32, vfov: f32) -> PanoramaCamera {
+        let (u, v, w) = look_at_basis(look_from, look_at, vup);
+        PanoramaCamera {
+            origin: look_from,
+            u,
+            v,
+            w,
+            hfov: hfov * PI / 180.0,
+            viewport_height: 2.0 * (vfov * PI / 360.0).tan(),
+        }
+    }
+}
+impl Camera for PanoramaCamera {
+    fn get_ray(&self, s: f32, t: f32, _sampler: &mut dyn Sampler) -> Option<(Ray, f32)> {
+        let phi = (s - 0.5) * self.hfov;
+        let height = (t - 0.5) * self.viewport_height;
+        let direction = phi.sin() * self.u + height * self.v - phi.cos() * self.w;
+        Some((Ray::new(self.origin, direction), 1.0))
+    }
+}
+
+// Latitude-longitude panorama covering hfov x 180 degrees: 360 for a full panorama, 180 for VR180. The film aspect
+// ratio should be hfov / 180. A non-zero eye_offset gives omni-directional stereo (ODS): the ray origin is shifted
+// sideways from look_from, perpendicular to the viewing direction of each column. Negative is the left eye.
+pub struct EquirectangularCamera {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    hfov: f32,
+    eye_offset: f32,
+}
+impl EquirectangularCamera {
+    pub fn new(look_from: Vec3, look_at: Vec3, vup: Vec3, hfov: f32, eye_offset: f32) -> EquirectangularCamera {
+        let (u, v, w) = look_at_basis(look_from, look_at, vup);
+        EquirectangularCamera { origin: look_from, u, v, w, hfov: hfov * PI / 180.0, eye_offset }
+    }
+}
+impl Camera for EquirectangularCamera {
+    fn get_ray(&self, s: f32, t: f32, _sampler: &mut dyn Sampler) -> Option<(Ray, f32)> {
+        let phi = (s - 0.5) * self.hfov;
+        let theta = (t - 0.5) * PI;
+        let direction = theta.cos() * (phi.sin() * self.u - phi.cos() * self.w) + theta.sin() * self.v;
+        let right = phi.cos() * self.u + phi.sin() * self.w;
+        Some((Ray::new(self.origin + self.eye_offset * right, direction), 1.0))
+    }
+}
+
+// Position and look-at point of one eye of a stereo rig placed with look_from and look_at. eye_offset is the signed
+// distance along the rig's right axis (negative for the left eye). Both eyes converge on the optical axis at the
+// convergence distance, or look parallel when it is infinite.
+pub fn stereo_eye(look_from: Vec3, look_at: Vec3, vup: Vec3, eye_offset: f32, convergence: f32) -> (Vec3, Vec3) {
+    let (u, _, w) = look_at_basis(look_from, look_at, vup);
+    let eye = look_from + eye_offset * u;
+    match convergence.is_finite() {
+        true => (eye, look_from - convergence * w),
+        false => (eye, eye + (look_at - look_from)),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum StereoLayout {
+    SideBySide, // left eye on the left half
+    OverUnder,  // left eye on the top half
+}
+
+// Renders 2 eye cameras into one film
+pub struct StereoCamera {
+    left: Box<dyn Camera>,
+    right: Box<dyn Camera>,
+    layout: StereoLayout,
+}
+impl StereoCamera {
+    pub fn new(left: Box<dyn Camera>, right: Box<dyn Camera>, layout: StereoLayout) -> StereoCamera {
+        StereoCamera { left, right, layout }
+    }
+}
+impl Camera for StereoCamera {
+    fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<(Ray, f32)> {
+        match self.layout {
+            StereoLayout::SideBySide if s < 0.5 => self.left.get_ray(2.0 * s, t, sampler),
+            StereoLayout::SideBySide => self.right.get_ray(2.0 * s - 1.0, t, sampler),
+            StereoLayout::OverUnder if t >= 0.5 => self.left.get_ray(s, 2.0 * t - 1.0, sampler),
+            StereoLayout::OverUnder => self.right.get_ray(s, 2.0 * t, sampler),
+        }
+    }
+    fn f_number(&self) -> Option<f32> {
+        self.left.f_number()
     }
 }
 
@@ -64,61 +422,241 @@ pub struct Renderer {
     pub img_width: u32,
     pub img_height: u32,
     samples_per_pixel: u32,
-    max_depth: u32,
+    integrator: Box<dyn Integrator>,
+    pub spectral: bool,
+    pub exposure: f32,
+    // adaptive sampling stops a pixel once its error is below the threshold, after at least min_samples_per_pixel
+    pub min_samples_per_pixel: u32,
+    pub noise_threshold: Option<f32>,
+    pub time_budget: Option<Duration>,
+    pub checkpoint: Option<Checkpoint>,
+    // only pixels in the crop window are rendered, along with a border whose samples the filter spreads into it, so
+    // that the window matches a full render exactly
+    pub crop: Option<CropWindow>,
+    // firefly suppression, biased so off by default
+    pub clamp_direct: Option<f32>,
+    pub clamp_indirect: Option<f32>,
 }
 impl Renderer {
-    pub fn new(img_width: u32, aspect_ratio: f32, samples_per_pixel: u32, max_depth: u32) -> Renderer {
+    const PASS_SAMPLES: u32 = 8; // samples per pixel in a pass, also the interval of adaptive convergence tests
+
+    pub fn new(img_width: u32, aspect_ratio: f32, samples_per_pixel: u32, integrator: Box<dyn Integrator>) -> Renderer {
         Renderer {
             img_width,
             img_height: (img_width as f32 / aspect_ratio) as u32,
             samples_per_pixel,
-            max_depth,
+            integrator,
+            spectral: false,
+            exposure: 1.0,
+            min_samples_per_pixel: samples_per_pixel,
+            noise_threshold: None,
+            time_budget: None,
+            checkpoint: None,
+            crop: None,
+            clamp_direct: None,
+            clamp_indirect: None,
         }
     }
-    pub fn render(&self, objects: &HittableList, camera: &Camera, buffer: &mut [u8]) {
-        for j in 0..self.img_height {
-            eprint!("Line {j}\r");
-            for i in 0..self.img_width {
-                let mut rng = PCG32::new(17 + j as u64, 23 + i as u64);
-
-                let mut pixel_color = Vec3::zero();
-                for _ in 0..self.samples_per_pixel {
-                    let u = (i as f32 + rng.f32()) / self.img_width as f32;
-                    let v = ((self.img_height - 1 - j) as f32 + rng.f32()) / self.img_height as f32;
+    // Renders in passes of up to PASS_SAMPLES samples per pixel, continuing from the samples already in the film. Stops
+    // once every pixel has samples_per_pixel samples or has converged, or when the time budget is used up.
+    pub fn render(&self, scene: &Scene, camera: &dyn Camera, sampler: &mut dyn Sampler, film: &mut Film) {
+        let start = Instant::now();
+        let mut last_checkpoint = start;
+        let region = match self.crop {
+            None => CropWindow { x0: 0, y0: 0, x1: self.img_width, y1: self.img_height },
+            Some(window) => CropWindow {
+                x0: window.x0.saturating_sub(film.border()),
+                y0: window.y0.saturating_sub(film.border()),
+                x1: (window.x1 + film.border()).min(self.img_width),
+                y1: (window.y1 + film.border()).min(self.img_height),
+            },
+        };
+        // passes continue the numbering of those the film already has samples from, e.g. after resuming from a
+        // checkpoint, so that integrators that change from pass to pass carry on where they left off
+        let passes_done = (region.y0..region.y1)
+            .flat_map(|j| (region.x0..region.x1).map(move |i| (i, j)))
+            .map(|(i, j)| film.sample_count(i, j))
+            .max()
+            .unwrap_or(0)
+            .div_ceil(Renderer::PASS_SAMPLES);
+        for pass in passes_done + 1.. {
+            let mut done = true;
+            for j in region.y0..region.y1 {
+                eprint!("Pass {pass}, line {j}\r");
+                for i in region.x0..region.x1 {
+                    let taken = film.sample_count(i, j);
+                    let converged = match self.noise_threshold {
+                        Some(threshold) => taken >= self.min_samples_per_pixel && film.error(i, j) < threshold,
+                        None => false,
+                    };
+                    if taken >= self.samples_per_pixel || converged {
+                        continue;
+                    }
+                    if done {
+                        // the first pixel sampled in this pass
+                        self.integrator.start_pass(scene, camera, pass);
+                        done = false;
+                    }
+                    for index in taken..(taken + Renderer::PASS_SAMPLES).min(self.samples_per_pixel) {
+                        self.sample_pixel(scene, camera, sampler, film, (i, j), index);
+                    }
+                }
+            }
+            if done {
+                break;
+            }
 
-                    let r = camera.get_ray(u, v, &mut rng);
-                    pixel_color = pixel_color + Renderer::ray_color(&r, objects, self.max_depth, &mut rng);
+            if let Some(checkpoint) = &self.checkpoint {
+                if last_checkpoint.elapsed() >= checkpoint.interval {
+                    Renderer::save_checkpoint(checkpoint, film);
+                    last_checkpoint = Instant::now();
                 }
-                pixel_color = pixel_color / self.samples_per_pixel as f32;
+            }
+            if self.time_budget.is_some_and(|budget| start.elapsed() >= budget) {
+                eprintln!("\nTime budget used up after {pass} passes");
+                break;
+            }
+        }
+        if let Some(checkpoint) = &self.checkpoint {
+            Renderer::save_checkpoint(checkpoint, film);
+        }
+    }
+    fn sample_pixel(
+        &self,
+        scene: &Scene,
+        camera: &dyn Camera,
+        sampler: &mut dyn Sampler,
+        film: &mut Film,
+        (i, j): (u32, u32),
+        index: u32,
+    ) {
+        sampler.start_pixel_sample((i, j), index);
+        let (dx, dy) = sampler.get_2d();
+        let x = i as f32 + dx;
+        let y = j as f32 + dy;
+        let u = x / self.img_width as f32;
+        let v = 1.0 - y / self.img_height as f32;
+
+        let ray = camera.get_ray(u, v, sampler);
+        if !film.aovs().is_empty() {
+            let rec = ray.as_ref().and_then(|(r, _)| first_surface(scene, r));
+            let values: Vec<Vec3> = match &ray {
+                None => vec![Vec3::zero(); film.aovs().len()],
+                Some((r, _)) => film.aovs().iter().map(|aov| aov.value(r, rec.as_ref())).collect(),
+            };
+            film.add_aovs((i, j), &values);
+        }
 
-                let offset = ((j * self.img_width + i) * 3) as usize;
-                buffer[offset] = (pixel_color.0.sqrt().clamp(0.0, 1.0) * 255.0) as u8;
-                buffer[offset + 1] = (pixel_color.1.sqrt().clamp(0.0, 1.0) * 255.0) as u8;
-                buffer[offset + 2] = (pixel_color.2.sqrt().clamp(0.0, 1.0) * 255.0) as u8;
+        let radiance = match ray {
+            None => Vec3::zero(),
+            Some((mut r, weight)) => {
+                let radiance = match self.spectral {
+                    true => {
+                        let wavelengths = Wavelengths::sample(sampler.get_1d());
+                        r.wavelengths = Some(wavelengths);
+                        let radiance = self.integrator.li(r, scene, camera, sampler);
+                        let wavelengths = radiance.wavelengths.unwrap_or(wavelengths);
+                        Radiance {
+                            direct: wavelengths.to_rgb(radiance.direct),
+                            indirect: wavelengths.to_rgb(radiance.indirect),
+                            splats: radiance
+                                .splats
+                                .into_iter()
+                                .map(|splat| Splat { radiance: wavelengths.to_rgb(splat.radiance), ..splat })
+                                .collect(),
+                            wavelengths: None,
+                        }
+                    }
+                    false => self.integrator.li(r, scene, camera, sampler),
+                };
+                // splats are counted against the samples of the whole film
+                let pixels = (self.img_width * self.img_height) as f32;
+                for splat in radiance.splats {
+                    let limit = match splat.bounces <= 1 {
+                        true => self.clamp_direct,
+                        false => self.clamp_indirect,
+                    };
+                    let value = Renderer::clamp(splat.radiance, limit) * (self.exposure * pixels);
+                    film.add_splat(
+                        splat.s * self.img_width as f32,
+                        (1.0 - splat.t) * self.img_height as f32,
+                        value,
+                    );
+                }
+                weight
+                    * (Renderer::clamp(radiance.direct, self.clamp_direct)
+                        + Renderer::clamp(radiance.indirect, self.clamp_indirect))
             }
+        };
+        film.add_sample((i, j), x, y, radiance * self.exposure);
+    }
+    // Direct and indirect light are clamped separately, scaled down by the largest component to keep the hue
+    fn clamp(radiance: Vec3, limit: Option<f32>) -> Vec3 {
+        let max = radiance.0.max(radiance.1).max(radiance.2);
+        match limit {
+            Some(limit) if max > limit => radiance * (limit / max),
+            _ => radiance,
         }
     }
-    fn ray_color(ray: &Ray, objects: &HittableList, depth: u32, rng: &mut PCG32) -> Vec3 {
-        if depth == 0 {
-            return Vec3::zero();
+    // a failed checkpoint should not end a long render
+    fn save_checkpoint(checkpoint: &Checkpoint, film: &Film) {
+        if let Err(e) = checkpoint.save(film) {
+            eprintln!("\nCannot write checkpoint {}: {e}", checkpoint.path);
         }
+    }
+}
 
-        match objects.hit(ray, 0.001, f32::INFINITY) {
-            None => {
-                // background
-                let unit_direction = ray.direction.normalize();
-                let t = 0.5 * (unit_direction.1 + 1.0);
-                let color1 = Vec3::one();
-                let color2 = Vec3(0.5, 0.7, 1.0);
-                color1 + t * (color2 - color1)
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::film::Filter;
+    use crate::hittable::{HittableList, Sphere};
+    use crate::integrator::PathIntegrator;
+    use crate::material::Lambertian;
+    use crate::sampler::IndependentSampler;
+    use crate::scene::Light;
+
+    fn render(crop: Option<CropWindow>) -> Film {
+        let mut objects = HittableList::new();
+        objects.push(Rc::new(Sphere::new(
+            Vec3(0.0, 1.0, 0.0),
+            1.0,
+            Rc::new(Lambertian::new(Vec3(0.8, 0.3, 0.3))),
+        )));
+        objects.push(Rc::new(Sphere::new(
+            Vec3(0.0, -1000.0, 0.0),
+            1000.0,
+            Rc::new(Lambertian::new(Vec3::one() * 0.5)),
+        )));
+        let scene = Scene::new(objects, vec![Light::Sky { scale: 1.0 }]);
+        let up = Vec3(0.0, 1.0, 0.0);
+        let camera = PerspectiveCamera::new(1.5, Vec3(0.0, 1.0, 6.0), Vec3(0.0, 1.0, 0.0), up, 40.0, 0.0, 6.0);
+
+        let mut renderer = Renderer::new(24, 1.5, 16, Box::new(PathIntegrator::mis(8, 3)));
+        renderer.crop = crop;
+        let mut film = Film::new(renderer.img_width, renderer.img_height, Filter::Gaussian, 2.0);
+        renderer.render(&scene, &camera, &mut IndependentSampler::new(), &mut film);
+        film
+    }
+
+    // the border around the window gets the filter the same samples from outside it as in the full render
+    #[test]
+    fn crop_matches_full_render() {
+        let window = CropWindow { x0: 5, y0: 3, x1: 17, y1: 11 };
+        let full = render(None);
+        let crop = render(Some(window)).cropped(window);
+        for j in window.y0..window.y1 {
+            for i in window.x0..window.x1 {
+                let (expected, actual) = (full.pixel(i, j), crop.pixel(i - window.x0, j - window.y0));
+                assert!(expected.length2() > 0.0);
+                assert_eq!(
+                    (actual.0, actual.1, actual.2),
+                    (expected.0, expected.1, expected.2),
+                    "pixel ({i}, {j})"
+                );
             }
-            Some(rec) => match rec.material.scatter(&ray.direction, &rec, rng) {
-                None => Vec3::zero(),
-                Some((scatter, color)) => {
-                    let scatter_ray = Ray::new(rec.p, scatter);
-                    color * Renderer::ray_color(&scatter_ray, objects, depth - 1, rng)
-                }
-            },
         }
     }
 }
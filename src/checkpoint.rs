@@ -0,0 +1,104 @@
+// Checkpoints of a progressive render. The film holds everything needed to continue: the accumulated radiance and,
+// as samplers are deterministic in the pixel and sample index, the per-pixel sample counts are the sampler state.
+// A key describing the render settings is stored so that a checkpoint is only resumed by the same render.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::time::Duration;
+
+use crate::film::Film;
+
+const MAGIC: &[u8; 8] = b"RTCHKPT2";
+
+pub struct Checkpoint {
+    pub path: String,
+    pub interval: Duration,
+    key: String,
+}
+
+impl Checkpoint {
+    pub fn new(path: &str, interval: Duration, key: String) -> Checkpoint {
+        Checkpoint { path: String::from(path), interval, key }
+    }
+
+    // written to a temporary file first, so that a crash while saving leaves the previous checkpoint intact
+    pub fn save(&self, film: &Film) -> io::Result<()> {
+        let temp_path = format!("{}.tmp", self.path);
+        let mut w = BufWriter::new(File::create(&temp_path)?);
+        w.write_all(MAGIC)?;
+        w.write_all(&(self.key.len() as u32).to_le_bytes())?;
+        w.write_all(self.key.as_bytes())?;
+        film.write_state(&mut w)?;
+        w.into_inner()?.sync_all()?;
+        fs::rename(&temp_path, &self.path)
+    }
+
+    // false if there is no checkpoint to resume from
+    pub fn load(&self, film: &mut Film) -> io::Result<bool> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let mut r = BufReader::new(file);
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        let mut len = [0; 4];
+        r.read_exact(&mut len)?;
+        let mut key = vec![0; u32::from_le_bytes(len) as usize];
+        r.read_exact(&mut key)?;
+        if &magic != MAGIC || key != self.key.as_bytes() {
+            let msg = format!("Not a checkpoint of this render ({})", String::from_utf8_lossy(&key));
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        film.read_state(&mut r)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::aov::Aov;
+    use crate::film::Filter;
+    use crate::vec3::Vec3;
+
+    fn film() -> Film {
+        Film::new(4, 3, Filter::Gaussian, 1.5).with_aovs(&[Aov::Normal, Aov::MaterialId])
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = env::temp_dir().join(format!("checkpoint-test-{}", std::process::id()));
+        let checkpoint = Checkpoint::new(path.to_str().unwrap(), Duration::ZERO, String::from("key"));
+        let mut saved = film();
+        for (n, (i, j)) in [(0, 0), (3, 2), (1, 1), (1, 1), (2, 0)].into_iter().enumerate() {
+            let value = Vec3(n as f32, 0.5, 1.0 / (n + 1) as f32);
+            saved.add_sample((i, j), i as f32 + 0.3, j as f32 + 0.6, value);
+            saved.add_aovs((i, j), &[value, Vec3::one() * n as f32]);
+        }
+        saved.add_splat(2.5, 1.5, Vec3(1.0, 2.0, 3.0));
+        checkpoint.save(&saved).unwrap();
+
+        let mut loaded = film();
+        assert!(checkpoint.load(&mut loaded).unwrap());
+        let other = Checkpoint::new(path.to_str().unwrap(), Duration::ZERO, String::from("other key"));
+        assert!(other.load(&mut film()).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(!checkpoint.load(&mut film()).unwrap());
+
+        for j in 0..3 {
+            for i in 0..4 {
+                let as_tuple = |v: Vec3| (v.0, v.1, v.2);
+                assert_eq!(as_tuple(loaded.pixel(i, j)), as_tuple(saved.pixel(i, j)));
+                assert_eq!(loaded.sample_count(i, j), saved.sample_count(i, j));
+                assert_eq!(loaded.error(i, j), saved.error(i, j));
+                for k in 0..2 {
+                    assert_eq!(as_tuple(loaded.aov_pixel(k, i, j)), as_tuple(saved.aov_pixel(k, i, j)));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// Debug integrators, which colour each pixel by a property of the first surface seen through it, or by the work it
+// took to find that surface, for tracking down mistakes in shading normals, texture coordinates and the BVH
+
+use std::str::FromStr;
+
+use crate::aov::Aov;
+use crate::camera::Camera;
+use crate::hittable::{take_traversal_counts, Ray};
+use crate::integrator::{first_surface, Integrator, Radiance};
+use crate::pcg32::PCG32;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::vec3::Vec3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DebugMode {
+    ShadingNormal,
+    Normal,
+    Uv,
+    Distance, // hit distance along the camera ray
+    MaterialId,
+    AabbTests, // heatmap of bounding boxes tested
+    BvhVisits, // heatmap of BVH nodes visited
+}
+
+impl FromStr for DebugMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DebugMode, String> {
+        match s {
+            "shading-normal" => Ok(DebugMode::ShadingNormal),
+            "normal" => Ok(DebugMode::Normal),
+            "uv" => Ok(DebugMode::Uv),
+            "distance" => Ok(DebugMode::Distance),
+            "material-id" => Ok(DebugMode::MaterialId),
+            "aabb-tests" => Ok(DebugMode::AabbTests),
+            "bvh-visits" => Ok(DebugMode::BvhVisits),
+            _ => Err(format!("Unknown debug mode {s}")),
+        }
+    }
+}
+
+impl DebugMode {
+    // the distance or count shown at full brightness, or in red on heatmaps, unless given
+    fn default_scale(self) -> f32 {
+        match self {
+            DebugMode::Distance => 20.0,
+            DebugMode::AabbTests => 200.0,
+            DebugMode::BvhVisits => 100.0,
+            _ => 1.0,
+        }
+    }
+
+    // the AOV that keeps the first sample's value, for modes whose values can not be averaged over a pixel
+    pub fn id_aov(self) -> Option<Aov> {
+        match self {
+            DebugMode::MaterialId => Some(Aov::MaterialId),
+            _ => None,
+        }
+    }
+
+    // Colour to view a value of DebugIntegrator with: unit vectors as (v + 1) / 2, UVs in red and green, distances in
+    // grey, IDs in colours that stay the same between renders and counts on a heatmap. Rays that missed everything
+    // are black, except on heatmaps.
+    pub fn display(self, value: Vec3, scale: Option<f32>) -> Vec3 {
+        let scale = scale.unwrap_or(self.default_scale());
+        match self {
+            DebugMode::ShadingNormal | DebugMode::Normal if value.length2() > 0.0 => (value + 1.0) * 0.5,
+            DebugMode::ShadingNormal | DebugMode::Normal => Vec3::zero(),
+            DebugMode::Uv => value,
+            DebugMode::Distance => Vec3::one() * (value.0 / scale).min(1.0),
+            DebugMode::MaterialId => match value.0.round() as u32 {
+                0 => Vec3::zero(),
+                id => Vec3::rand_between(&mut PCG32::new(id as u64, 0), 0.2, 1.0),
+            },
+            DebugMode::AabbTests | DebugMode::BvhVisits => heatmap(value.0 / scale),
+        }
+    }
+}
+
+// Colours from blue through cyan, green and yellow to red for x from 0 to 1
+fn heatmap(x: f32) -> Vec3 {
+    const COLORS: [Vec3; 5] = [
+        Vec3(0.0, 0.0, 1.0),
+        Vec3(0.0, 1.0, 1.0),
+        Vec3(0.0, 1.0, 0.0),
+        Vec3(1.0, 1.0, 0.0),
+        Vec3(1.0, 0.0, 0.0),
+    ];
+    let x = x.clamp(0.0, 1.0) * (COLORS.len() - 1) as f32;
+    let i = (x as usize).min(COLORS.len() - 2);
+    let f = x - i as f32;
+    COLORS[i] * (1.0 - f) + COLORS[i + 1] * f
+}
+
+// The value itself in every channel that it has, e.g. the normal in x, y and z, or a count or ID in all three, for
+// the renderer to write out as it is. DebugMode::display turns it into a colour. Rays that miss everything give zero.
+pub struct DebugIntegrator {
+    mode: DebugMode,
+}
+impl DebugIntegrator {
+    pub fn new(mode: DebugMode) -> DebugIntegrator {
+        DebugIntegrator { mode }
+    }
+}
+impl Integrator for DebugIntegrator {
+    fn li(&self, ray: Ray, scene: &Scene, _camera: &dyn Camera, _sampler: &mut dyn Sampler) -> Radiance {
+        let mut radiance = Radiance::zero();
+        take_traversal_counts();
+        let rec = first_surface(scene, &ray);
+        let counts = take_traversal_counts();
+        let value = match (self.mode, rec) {
+            (DebugMode::AabbTests, _) => Vec3::one() * counts.aabb_tests as f32,
+            (DebugMode::BvhVisits, _) => Vec3::one() * counts.bvh_visits as f32,
+            (_, None) => Vec3::zero(),
+            (DebugMode::ShadingNormal, Some(rec)) => rec.material.shading_normal(&rec),
+            (DebugMode::Normal, Some(rec)) => rec.geometric_normal,
+            (DebugMode::Uv, Some(rec)) => Vec3(rec.uv.0, rec.uv.1, 0.0),
+            (DebugMode::Distance, Some(rec)) => Vec3::one() * rec.t * ray.direction.length(),
+            (DebugMode::MaterialId, Some(rec)) => Vec3::one() * rec.material_id as f32,
+        };
+        radiance.add(0, value);
+        radiance
+    }
+}
@@ -0,0 +1,165 @@
+// Edge-avoiding A-Trous wavelet denoiser (Dammertz et al. 2010), guided by the albedo, normal and depth AOVs. The
+// colour is divided by the albedo before filtering so that texture detail is kept, and multiplied back afterwards.
+
+use std::io;
+
+use crate::aov::Aov;
+use crate::exr::read_exr;
+use crate::film::Film;
+use crate::vec3::Vec3;
+
+// a float framebuffer with the feature buffers guiding the filter
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub color: Vec<Vec3>,
+    albedo: Vec<Vec3>,
+    normal: Vec<Vec3>,
+    depth: Vec<f32>,
+}
+
+impl Frame {
+    pub const AOVS: [Aov; 3] = [Aov::Albedo, Aov::Normal, Aov::Depth];
+
+    // the film must have the albedo, normal and depth AOVs
+    pub fn from_film(film: &Film) -> Frame {
+        let pixels = || (0..film.height).flat_map(|j| (0..film.width).map(move |i| (i, j)));
+        let aov = |aov: Aov| {
+            let k = film
+                .aovs()
+                .iter()
+                .position(|&a| a == aov)
+                .expect("Denoising requires the albedo, normal and depth AOVs");
+            pixels().map(move |(i, j)| film.aov_pixel(k, i, j))
+        };
+        Frame {
+            width: film.width,
+            height: film.height,
+            color: pixels().map(|(i, j)| film.pixel(i, j)).collect(),
+            albedo: aov(Aov::Albedo).collect(),
+            normal: aov(Aov::Normal).map(unit_or_zero).collect(),
+            depth: aov(Aov::Depth).map(|d| d.0).collect(),
+        }
+    }
+
+    // multi-layer EXR as written with --aovs albedo,normal,depth
+    pub fn read_exr(path: &str) -> io::Result<Frame> {
+        let (width, height, channels) = read_exr(path)?;
+        let channel = |name: &str| {
+            channels
+                .iter()
+                .find(|c| c.name == name)
+                .map(|c| &c.values)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Missing channel {name}")))
+        };
+        let vec3s = |names: [&str; 3]| -> io::Result<Vec<Vec3>> {
+            let (x, y, z) = (channel(names[0])?, channel(names[1])?, channel(names[2])?);
+            Ok((0..x.len()).map(|k| Vec3(x[k], y[k], z[k])).collect())
+        };
+        Ok(Frame {
+            width,
+            height,
+            color: vec3s(["R", "G", "B"])?,
+            albedo: vec3s(["albedo.R", "albedo.G", "albedo.B"])?,
+            normal: vec3s(["normal.X", "normal.Y", "normal.Z"])?
+                .into_iter()
+                .map(unit_or_zero)
+                .collect(),
+            depth: channel("distance.Z")?.clone(),
+        })
+    }
+}
+
+pub struct Denoiser {
+    pub iterations: u32,
+    pub sigma_color: f32,  // of gamma 2 encoded illumination, halved every iteration
+    pub normal_power: f32, // weight is the cosine between normals to this power
+    pub sigma_depth: f32,  // relative depth difference
+    pub sigma_albedo: f32,
+}
+
+impl Default for Denoiser {
+    fn default() -> Denoiser {
+        Denoiser {
+            iterations: 4,
+            sigma_color: 0.2,
+            normal_power: 64.0,
+            sigma_depth: 0.02,
+            sigma_albedo: 0.1,
+        }
+    }
+}
+
+impl Denoiser {
+    // B3 spline, the 5x5 kernel is the outer product of this with itself
+    const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+    pub fn denoise(&self, frame: &Frame) -> Vec<Vec3> {
+        // background and black surfaces are not demodulated
+        let albedo: Vec<Vec3> = frame
+            .albedo
+            .iter()
+            .map(|&a| match a.0.max(a.1).max(a.2) < 1e-3 {
+                true => Vec3::one(),
+                false => Vec3(a.0.max(1e-3), a.1.max(1e-3), a.2.max(1e-3)),
+            })
+            .collect();
+        let mut illumination: Vec<Vec3> = frame.color.iter().zip(&albedo).map(|(&c, &a)| c / a).collect();
+
+        let (width, height) = (frame.width as i64, frame.height as i64);
+        let mut sigma_color = self.sigma_color;
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            let encoded: Vec<Vec3> = illumination.iter().map(|&c| gamma_encode(c)).collect();
+            let mut filtered = vec![Vec3::zero(); illumination.len()];
+            for y in 0..height {
+                for x in 0..width {
+                    let p = (y * width + x) as usize;
+                    let (mut sum, mut total) = (Vec3::zero(), 0.0);
+                    for (dy, ky) in Denoiser::KERNEL.iter().enumerate() {
+                        for (dx, kx) in Denoiser::KERNEL.iter().enumerate() {
+                            let qx = x + (dx as i64 - 2) * step;
+                            let qy = y + (dy as i64 - 2) * step;
+                            if qx < 0 || qx >= width || qy < 0 || qy >= height {
+                                continue;
+                            }
+                            let q = (qy * width + qx) as usize;
+                            let weight = kx * ky * self.weight(frame, &encoded, sigma_color, p, q);
+                            sum = sum + weight * illumination[q];
+                            total += weight;
+                        }
+                    }
+                    filtered[p] = sum / total;
+                }
+            }
+            illumination = filtered;
+            sigma_color *= 0.5;
+        }
+        illumination.iter().zip(&albedo).map(|(&c, &a)| c * a).collect()
+    }
+
+    // edge stopping weight between pixels p and q
+    fn weight(&self, frame: &Frame, encoded: &[Vec3], sigma_color: f32, p: usize, q: usize) -> f32 {
+        let color = (encoded[p] - encoded[q]).length2() / (sigma_color * sigma_color);
+        let albedo = (frame.albedo[p] - frame.albedo[q]).length2() / (self.sigma_albedo * self.sigma_albedo);
+        let depth = (frame.depth[p] - frame.depth[q]).abs() / (self.sigma_depth * frame.depth[p].max(1e-3));
+        let (np, nq) = (frame.normal[p], frame.normal[q]);
+        let normal = match np.length2() == 0.0 && nq.length2() == 0.0 {
+            true => 1.0,
+            false => np.dot(nq).max(0.0).powf(self.normal_power),
+        };
+        normal * (-color - albedo - depth).exp()
+    }
+}
+
+// normals averaged over a pixel are shorter than unit length, and zero where the camera ray left the scene
+fn unit_or_zero(n: Vec3) -> Vec3 {
+    match n.length2() > 1e-12 {
+        true => n.normalize(),
+        false => Vec3::zero(),
+    }
+}
+
+fn gamma_encode(c: Vec3) -> Vec3 {
+    Vec3(c.0.max(0.0).sqrt(), c.1.max(0.0).sqrt(), c.2.max(0.0).sqrt())
+}
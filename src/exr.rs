@@ -0,0 +1,216 @@
+// A simple OpenEXR encoder and decoder: single part scanline image, 32-bit float channels, no compression
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+
+// channel name and its width * height values, top row first. Names may carry a layer prefix e.g. `normal.X`.
+pub struct Channel {
+    pub name: String,
+    pub values: Vec<f32>,
+}
+
+fn attribute(w: &mut dyn Write, name: &str, kind: &str, value: &[u8]) -> io::Result<()> {
+    w.write_all(name.as_bytes())?;
+    w.write_all(&[0])?;
+    w.write_all(kind.as_bytes())?;
+    w.write_all(&[0])?;
+    w.write_all(&(value.len() as i32).to_le_bytes())?;
+    w.write_all(value)
+}
+
+pub fn write_exr(path: &str, width: u32, height: u32, channels: &mut [Channel]) -> io::Result<()> {
+    const FLOAT: i32 = 2;
+    // channels are stored in alphabetical order
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut chlist = Vec::new();
+    for channel in channels.iter() {
+        chlist.extend_from_slice(channel.name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&FLOAT.to_le_bytes());
+        chlist.extend_from_slice(&[0, 0, 0, 0]); // pLinear and reserved
+        chlist.extend_from_slice(&1_i32.to_le_bytes()); // x and y sampling
+        chlist.extend_from_slice(&1_i32.to_le_bytes());
+    }
+    chlist.push(0);
+    let window: Vec<u8> = [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+
+    // magic number and version 2, single part scanline
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+    attribute(&mut header, "channels", "chlist", &chlist)?;
+    attribute(&mut header, "compression", "compression", &[0])?;
+    attribute(&mut header, "dataWindow", "box2i", &window)?;
+    attribute(&mut header, "displayWindow", "box2i", &window)?;
+    attribute(&mut header, "lineOrder", "lineOrder", &[0])?;
+    attribute(&mut header, "pixelAspectRatio", "float", &1.0_f32.to_le_bytes())?;
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+    attribute(&mut header, "screenWindowWidth", "float", &1.0_f32.to_le_bytes())?;
+    header.push(0);
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(&header)?;
+
+    // offset table of the scanlines, then each scanline with its channels one after the other
+    let line_size = 8 + 4 * width as usize * channels.len();
+    let first_line = header.len() + 8 * height as usize;
+    for y in 0..height as usize {
+        w.write_all(&((first_line + y * line_size) as u64).to_le_bytes())?;
+    }
+    for y in 0..height as usize {
+        w.write_all(&(y as i32).to_le_bytes())?;
+        w.write_all(&((line_size - 8) as i32).to_le_bytes())?;
+        for channel in channels.iter() {
+            for value in &channel.values[y * width as usize..(y + 1) * width as usize] {
+                w.write_all(&value.to_le_bytes())?;
+            }
+        }
+    }
+    w.flush()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// reads the files write_exr writes: single part scanline images with uncompressed 32-bit float channels
+pub fn read_exr(path: &str) -> io::Result<(u32, u32, Vec<Channel>)> {
+    let data = fs::read(path)?;
+    let u32_at = |pos: usize| -> io::Result<u32> {
+        let bytes = data.get(pos..pos + 4).ok_or_else(|| invalid("Truncated file"))?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let string_at = |pos: usize| -> io::Result<String> {
+        let rest = data.get(pos..).ok_or_else(|| invalid("Truncated header"))?;
+        let end = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| invalid("Truncated header"))?;
+        Ok(String::from_utf8_lossy(&rest[..end]).into_owned())
+    };
+    if data.get(0..8) != Some(&[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]) {
+        return Err(invalid("Not a single part scanline OpenEXR file"));
+    }
+
+    let (mut names, mut window, mut compression) = (Vec::new(), None, None);
+    let mut pos = 8;
+    while *data.get(pos).ok_or_else(|| invalid("Truncated header"))? != 0 {
+        let name = string_at(pos)?;
+        let kind = string_at(pos + name.len() + 1)?;
+        pos += name.len() + kind.len() + 2;
+        let size = u32_at(pos)? as usize;
+        pos += 4;
+        match name.as_str() {
+            "channels" => {
+                let mut c = pos;
+                while *data.get(c).ok_or_else(|| invalid("Truncated header"))? != 0 {
+                    let channel = string_at(c)?;
+                    if u32_at(c + channel.len() + 1)? != 2 {
+                        return Err(invalid("Only 32-bit float channels are supported"));
+                    }
+                    c += channel.len() + 1 + 16;
+                    names.push(channel);
+                }
+            }
+            "compression" => compression = data.get(pos).copied(),
+            "dataWindow" => window = Some([u32_at(pos)?, u32_at(pos + 4)?, u32_at(pos + 8)?, u32_at(pos + 12)?]),
+            _ => (),
+        }
+        pos += size;
+    }
+    if compression != Some(0) {
+        return Err(invalid("Only uncompressed files are supported"));
+    }
+    // the window's corners are signed and inclusive. The pixels are checked against the file size so that a broken
+    // header can not ask for more memory than the file could fill.
+    let [x0, y0, x1, y1] = window.ok_or_else(|| invalid("Missing data window"))?.map(|v| v as i32);
+    let size = |min: i32, max: i32| max.checked_sub(min)?.checked_add(1).filter(|&n| n > 0);
+    let (width, height) = match (size(x0, x1), size(y0, y1)) {
+        (Some(width), Some(height)) => (width as u32, height as u32),
+        _ => return Err(invalid("Invalid data window")),
+    };
+    let pixels = (width as usize)
+        .checked_mul(height as usize)
+        .filter(|&n| n.saturating_mul(4 * names.len()) <= data.len())
+        .ok_or_else(|| invalid("Truncated file"))?;
+
+    let mut channels: Vec<Channel> = names
+        .into_iter()
+        .map(|name| Channel { name, values: Vec::with_capacity(pixels) })
+        .collect();
+    let offsets = pos + 1;
+    for y in 0..height as usize {
+        let line = u32_at(offsets + 8 * y)? as usize + 8;
+        for (k, channel) in channels.iter_mut().enumerate() {
+            for x in 0..width as usize {
+                channel
+                    .values
+                    .push(f32::from_bits(u32_at(line + 4 * (k * width as usize + x))?));
+            }
+        }
+    }
+    Ok((width, height, channels))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn write_and_read_round_trip() {
+        let path = env::temp_dir().join(format!("exr-test-{}.exr", std::process::id()));
+        let path = path.to_str().unwrap();
+        let (width, height) = (3, 2);
+        let channel = |name: &str, offset: f32| Channel {
+            name: String::from(name),
+            values: (0..width * height).map(|n| offset + n as f32 * 0.25).collect(),
+        };
+        let mut channels = [
+            channel("R", 0.0),
+            channel("G", -1.0),
+            channel("normal.X", 1e-3),
+            channel("B", 1e6),
+        ];
+        write_exr(path, width, height, &mut channels).unwrap();
+        let read = read_exr(path);
+        fs::remove_file(path).unwrap();
+
+        let (read_width, read_height, read_channels) = read.unwrap();
+        assert_eq!((read_width, read_height), (width, height));
+        assert_eq!(read_channels.len(), channels.len());
+        for (read, written) in read_channels.iter().zip(&channels) {
+            assert_eq!(read.name, written.name);
+            assert_eq!(read.values, written.values);
+        }
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        let path = env::temp_dir().join(format!("exr-malformed-test-{}.exr", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut channels = [Channel { name: String::from("Y"), values: vec![0.5; 6] }];
+        write_exr(path, 3, 2, &mut channels).unwrap();
+        let data = fs::read(path).unwrap();
+        let window = data.windows(10).position(|w| w == b"dataWindow").unwrap() + 21;
+
+        let mut files: Vec<Vec<u8>> = (0..data.len()).map(|n| data[..n].to_vec()).collect();
+        for corners in [
+            [0, 0, -1, 1],
+            [0, 0, 2, -5],
+            [i32::MIN, 0, i32::MAX, 1],
+            [0, 0, 1 << 20, 1 << 20],
+        ] {
+            let mut file = data.clone();
+            let bytes: Vec<u8> = corners.iter().flat_map(|v| v.to_le_bytes()).collect();
+            file[window..window + 16].copy_from_slice(&bytes);
+            files.push(file);
+        }
+        for file in files {
+            fs::write(path, &file).unwrap();
+            assert!(read_exr(path).is_err(), "{} bytes", file.len());
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,375 @@
+// Film accumulating radiance samples with a pixel reconstruction filter. A sample contributes to every pixel whose
+// centre is within the filter radius, weighted by the filter, and each pixel is normalised by its total weight.
+
+use std::f32::consts::PI;
+use std::io::{self, Read, Write};
+
+use crate::aov::Aov;
+use crate::vec3::Vec3;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell, // Mitchell-Netravali with B = C = 1/3
+    Lanczos,
+}
+
+impl Filter {
+    // 1D filter value at offset x from the pixel centre, x in [-radius, radius]
+    fn eval_1d(&self, x: f32, radius: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            Filter::Box => 1.0,
+            Filter::Tent => radius - x,
+            Filter::Gaussian => {
+                let sigma = radius / 3.0;
+                let gaussian = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                gaussian(x) - gaussian(radius)
+            }
+            Filter::Mitchell => {
+                let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+                let x = 2.0 * x / radius;
+                match x > 1.0 {
+                    true => {
+                        ((-b - 6.0 * c) * x * x * x
+                            + (6.0 * b + 30.0 * c) * x * x
+                            + (-12.0 * b - 48.0 * c) * x
+                            + (8.0 * b + 24.0 * c))
+                            / 6.0
+                    }
+                    false => {
+                        ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                            + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                            + (6.0 - 2.0 * b))
+                            / 6.0
+                    }
+                }
+            }
+            Filter::Lanczos => {
+                let sinc = |x: f32| match x < 1e-5 {
+                    true => 1.0,
+                    false => (PI * x).sin() / (PI * x),
+                };
+                sinc(x) * sinc(x / radius)
+            }
+        }
+    }
+}
+
+// Region of the image in pixels, x0..x1 by y0..y1 with y pointing down
+#[derive(Debug, Clone, Copy)]
+pub struct CropWindow {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl CropWindow {
+    // from coordinates in [0, 1], y pointing down, rounded to the nearest pixel edges
+    pub fn from_normalized([x0, y0, x1, y1]: [f32; 4], width: u32, height: u32) -> CropWindow {
+        let to_pixels = |v: f32, size: u32| (v.clamp(0.0, 1.0) * size as f32).round() as u32;
+        CropWindow {
+            x0: to_pixels(x0, width),
+            y0: to_pixels(y0, height),
+            x1: to_pixels(x1, width),
+            y1: to_pixels(y1, height),
+        }
+    }
+}
+
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    filter: Filter,
+    radius: f32,
+    sums: Vec<Vec3>,
+    weights: Vec<f32>,
+    // running statistics of the luminance of the samples taken in each pixel (Welford's algorithm)
+    sample_counts: Vec<u32>,
+    means: Vec<f32>,
+    m2s: Vec<f32>,
+    // per AOV the sum of its values over the pixel's samples, or the first sample's value for IDs
+    aovs: Vec<Aov>,
+    aov_values: Vec<Vec<Vec3>>,
+    // light tracing contributions, unfiltered, and the number of samples taken over the whole film, which each may
+    // have splatted anywhere
+    splats: Vec<Vec3>,
+    samples_taken: u64,
+}
+
+impl Film {
+    const UNCONVERGED_ERROR: f32 = 0.2; // relative standard error
+
+    pub fn new(width: u32, height: u32, filter: Filter, radius: f32) -> Film {
+        let n = (width * height) as usize;
+        Film {
+            width,
+            height,
+            filter,
+            radius,
+            sums: vec![Vec3::zero(); n],
+            weights: vec![0.0; n],
+            sample_counts: vec![0; n],
+            means: vec![0.0; n],
+            m2s: vec![0.0; n],
+            aovs: Vec::new(),
+            aov_values: Vec::new(),
+            splats: vec![Vec3::zero(); n],
+            samples_taken: 0,
+        }
+    }
+    pub fn with_aovs(mut self, aovs: &[Aov]) -> Film {
+        let n = (self.width * self.height) as usize;
+        self.aovs = aovs.to_vec();
+        self.aov_values = vec![vec![Vec3::zero(); n]; aovs.len()];
+        self
+    }
+    pub fn aovs(&self) -> &[Aov] {
+        &self.aovs
+    }
+
+    // pixels outside a region whose samples still reach into it through the filter
+    pub fn border(&self) -> u32 {
+        (self.radius - 0.5).ceil().max(0.0) as u32
+    }
+
+    // copy of the pixels in the window, with their accumulated state
+    pub fn cropped(&self, window: CropWindow) -> Film {
+        let mut film =
+            Film::new(window.x1 - window.x0, window.y1 - window.y0, self.filter, self.radius).with_aovs(&self.aovs);
+        for j in window.y0..window.y1 {
+            for i in window.x0..window.x1 {
+                let (from, to) = (
+                    (j * self.width + i) as usize,
+                    ((j - window.y0) * film.width + i - window.x0) as usize,
+                );
+                film.sums[to] = self.sums[from];
+                film.weights[to] = self.weights[from];
+                film.sample_counts[to] = self.sample_counts[from];
+                film.means[to] = self.means[from];
+                film.m2s[to] = self.m2s[from];
+                film.splats[to] = self.splats[from];
+                for (values, from_values) in film.aov_values.iter_mut().zip(&self.aov_values) {
+                    values[to] = from_values[from];
+                }
+            }
+        }
+        film.samples_taken = self.samples_taken;
+        film
+    }
+
+    // x and y are continuous raster coordinates, y pointing down. pixel (i, j) covers [i, i + 1) x [j, j + 1). The
+    // sample is counted for the pixel it was taken for, as x and y may round to the edge of the next pixel.
+    pub fn add_sample(&mut self, (i, j): (u32, u32), x: f32, y: f32, radiance: Vec3) {
+        let index = (j * self.width + i) as usize;
+        let luminance = radiance.luminance();
+        self.samples_taken += 1;
+        self.sample_counts[index] += 1;
+        let delta = luminance - self.means[index];
+        self.means[index] += delta / self.sample_counts[index] as f32;
+        self.m2s[index] += delta * (luminance - self.means[index]);
+
+        let x0 = ((x - 0.5 - self.radius).floor() as i64 + 1).max(0);
+        let x1 = ((x - 0.5 + self.radius).floor() as i64).min(self.width as i64 - 1);
+        let y0 = ((y - 0.5 - self.radius).floor() as i64 + 1).max(0);
+        let y1 = ((y - 0.5 + self.radius).floor() as i64).min(self.height as i64 - 1);
+
+        // pixels with centre offsets in (-radius, radius]
+        for j in y0..=y1 {
+            let fy = self.filter.eval_1d(j as f32 + 0.5 - y, self.radius);
+            for i in x0..=x1 {
+                let weight = self.filter.eval_1d(i as f32 + 0.5 - x, self.radius) * fy;
+                let index = (j * self.width as i64 + i) as usize;
+                self.sums[index] = self.sums[index] + weight * radiance;
+                self.weights[index] += weight;
+            }
+        }
+    }
+
+    // Light arriving at continuous raster coordinates x and y, scaled so that the pixel's value is the sum of its
+    // splats divided by the number of samples taken over the film
+    pub fn add_splat(&mut self, x: f32, y: f32, radiance: Vec3) {
+        let i = (x as u32).min(self.width - 1);
+        let j = (y as u32).min(self.height - 1);
+        let index = (j * self.width + i) as usize;
+        self.splats[index] = self.splats[index] + radiance;
+    }
+
+    // Fireflies: pixels brighter than k standard deviations above the mean of their 8 neighbours, whose own samples
+    // have not converged, i.e. whose value rests on a few bright samples. Converged highlights are kept. Outliers are
+    // scaled down to that bound.
+    pub fn reject_outliers(&mut self, k: f32) {
+        let luminances: Vec<f32> = (0..self.height)
+            .flat_map(|j| (0..self.width).map(move |i| (i, j)))
+            .map(|(i, j)| self.pixel(i, j).luminance())
+            .collect();
+        let mut rejected = 0;
+        for j in 0..self.height {
+            for i in 0..self.width {
+                let index = (j * self.width + i) as usize;
+                let neighbours: Vec<f32> = (j.saturating_sub(1)..(j + 2).min(self.height))
+                    .flat_map(|y| (i.saturating_sub(1)..(i + 2).min(self.width)).map(move |x| (x, y)))
+                    .filter(|&(x, y)| (x, y) != (i, j))
+                    .map(|(x, y)| luminances[(y * self.width + x) as usize])
+                    .collect();
+                let mean = neighbours.iter().sum::<f32>() / neighbours.len() as f32;
+                let variance =
+                    neighbours.iter().map(|l| (l - mean) * (l - mean)).sum::<f32>() / neighbours.len() as f32;
+                let bound = mean + k * variance.sqrt();
+
+                let (luminance, n) = (luminances[index], self.sample_counts[index]);
+                let relative_error = match n > 1 {
+                    true => (self.m2s[index] / (n - 1) as f32 / n as f32).sqrt() / luminance.max(1e-4),
+                    false => f32::INFINITY,
+                };
+                if luminance > bound && relative_error > Film::UNCONVERGED_ERROR {
+                    self.sums[index] = self.sums[index] * (bound / luminance);
+                    rejected += 1;
+                }
+            }
+        }
+        eprintln!("Rejected {rejected} outlier pixels");
+    }
+
+    // AOV values of a sample, one per AOV of the film. Must come before add_sample for the same sample.
+    pub fn add_aovs(&mut self, (i, j): (u32, u32), values: &[Vec3]) {
+        let index = (j * self.width + i) as usize;
+        let first = self.sample_counts[index] == 0;
+        for ((aov, aov_values), &value) in self.aovs.iter().zip(self.aov_values.iter_mut()).zip(values) {
+            match aov.is_id() {
+                true if first => aov_values[index] = value,
+                true => (),
+                false => aov_values[index] = aov_values[index] + value,
+            }
+        }
+    }
+
+    // the k-th AOV averaged over the pixel's samples
+    pub fn aov_pixel(&self, k: usize, i: u32, j: u32) -> Vec3 {
+        let index = (j * self.width + i) as usize;
+        let value = self.aov_values[k][index];
+        match (self.aovs[k].is_id(), self.sample_counts[index]) {
+            (true, _) | (false, 0) => value,
+            (false, n) => value / n as f32,
+        }
+    }
+
+    pub fn pixel(&self, i: u32, j: u32) -> Vec3 {
+        let index = (j * self.width + i) as usize;
+        let splat = match self.samples_taken {
+            0 => Vec3::zero(),
+            n => self.splats[index] / n as f32,
+        };
+        match self.weights[index] != 0.0 {
+            true => self.sums[index] / self.weights[index] + splat,
+            false => splat,
+        }
+    }
+
+    pub fn sample_count(&self, i: u32, j: u32) -> u32 {
+        self.sample_counts[(j * self.width + i) as usize]
+    }
+
+    // standard error of the pixel's mean luminance after gamma 2 encoding, d sqrt(L) = dL / (2 sqrt(L)), so that
+    // errors are judged the way they are displayed
+    pub fn error(&self, i: u32, j: u32) -> f32 {
+        let index = (j * self.width + i) as usize;
+        let n = self.sample_counts[index];
+        if n < 2 {
+            return f32::INFINITY;
+        }
+        let variance = self.m2s[index] / (n - 1) as f32;
+        (variance / n as f32).sqrt() / (2.0 * self.means[index].max(1e-4).sqrt())
+    }
+
+    // samples taken per pixel as 8-bit grey, white being the largest count
+    pub fn sample_counts_to_rgb8(&self) -> Vec<u8> {
+        let max_count = self.sample_counts.iter().copied().max().unwrap_or(0).max(1);
+        self.sample_counts
+            .iter()
+            .flat_map(|&count| [(count as f32 / max_count as f32 * 255.0) as u8; 3])
+            .collect()
+    }
+
+    // accumulated state as little endian binary, see read_state
+    pub fn write_state(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.width.to_le_bytes())?;
+        w.write_all(&self.height.to_le_bytes())?;
+        for index in 0..self.sums.len() {
+            let (sum, splat) = (self.sums[index], self.splats[index]);
+            for value in [
+                sum.0,
+                sum.1,
+                sum.2,
+                self.weights[index],
+                self.means[index],
+                self.m2s[index],
+                splat.0,
+                splat.1,
+                splat.2,
+            ] {
+                w.write_all(&value.to_le_bytes())?;
+            }
+            w.write_all(&self.sample_counts[index].to_le_bytes())?;
+            for values in &self.aov_values {
+                for value in [values[index].0, values[index].1, values[index].2] {
+                    w.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // replaces the accumulated state with one written by write_state from a film of the same size
+    pub fn read_state(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        let read_u32 = |r: &mut dyn Read| -> io::Result<u32> {
+            let mut bytes = [0; 4];
+            r.read_exact(&mut bytes)?;
+            Ok(u32::from_le_bytes(bytes))
+        };
+        if (read_u32(r)?, read_u32(r)?) != (self.width, self.height) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Film size does not match"));
+        }
+        for index in 0..self.sums.len() {
+            let mut values = [0.0; 9];
+            for value in values.iter_mut() {
+                *value = f32::from_bits(read_u32(r)?);
+            }
+            self.sums[index] = Vec3(values[0], values[1], values[2]);
+            (self.weights[index], self.means[index], self.m2s[index]) = (values[3], values[4], values[5]);
+            self.splats[index] = Vec3(values[6], values[7], values[8]);
+            self.sample_counts[index] = read_u32(r)?;
+            for values in self.aov_values.iter_mut() {
+                let (x, y, z) = (read_u32(r)?, read_u32(r)?, read_u32(r)?);
+                values[index] = Vec3(f32::from_bits(x), f32::from_bits(y), f32::from_bits(z));
+            }
+        }
+        self.samples_taken = self.sample_counts.iter().map(|&n| n as u64).sum();
+        Ok(())
+    }
+
+    // gamma 2 encoded 8-bit RGB
+    pub fn to_rgb8(&self) -> Vec<u8> {
+        let pixels = (0..self.height).flat_map(|j| (0..self.width).map(move |i| self.pixel(i, j)));
+        encode_rgb8(pixels)
+    }
+}
+
+// gamma 2 encoded 8-bit RGB of linear colours
+pub fn encode_rgb8(pixels: impl Iterator<Item = Vec3>) -> Vec<u8> {
+    pixels
+        .flat_map(|color| [color.0, color.1, color.2])
+        .map(|c| (c.max(0.0).sqrt().min(1.0) * 255.0) as u8)
+        .collect()
+}
+
+// 8-bit RGB without gamma, of colours that encode values
+pub fn encode_linear_rgb8(pixels: impl Iterator<Item = Vec3>) -> Vec<u8> {
+    pixels
+        .flat_map(|color| [color.0, color.1, color.2])
+        .map(|c| (c.clamp(0.0, 1.0) * 255.0) as u8)
+        .collect()
+}
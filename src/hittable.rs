@@ -1,36 +1,101 @@
+use std::cell::Cell;
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::f32::consts::PI;
 use std::mem;
 use std::ops::Index;
 use std::rc::Rc;
 
 use crate::material::Material;
+use crate::medium::MediumInterface;
 use crate::pcg32::PCG32;
+use crate::spectrum::Wavelengths;
+use crate::texture::Texture;
 use crate::vec3::Vec3;
 
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
+    pub wavelengths: Option<Wavelengths>, // None for RGB rendering
 }
 
 #[rustfmt::skip]
 impl Ray {
-    pub fn new(origin: Vec3, direction: Vec3) -> Ray { Ray { origin, direction } }
+    pub fn new(origin: Vec3, direction: Vec3) -> Ray { Ray { origin, direction, wavelengths: None } }
     pub fn at(&self, t: f32) -> Vec3 { self.origin + self.direction * t }
 }
+impl Ray {
+    const OFFSET: f32 = 1e-4;
+
+    // continue the path from a hit point, carrying over the sampled wavelengths
+    pub fn spawn(&self, rec: &HitRecord, direction: Vec3) -> Ray {
+        let mut wavelengths = self.wavelengths;
+        if let Some(wavelengths) = wavelengths.as_mut() {
+            if rec.material.is_dispersive() {
+                wavelengths.terminate_secondary();
+            }
+        }
+        // offset along the geometric normal to avoid self-intersection, also when the shading normal disagrees
+        let offset = match direction.dot(rec.geometric_normal) > 0.0 {
+            true => rec.geometric_normal * Ray::OFFSET,
+            false => rec.geometric_normal * -Ray::OFFSET,
+        };
+        Ray { origin: rec.p + offset, direction, wavelengths }
+    }
+    // convert an RGB colour to the colour space the path is traced in
+    pub fn spectrum(&self, rgb: Vec3) -> Vec3 {
+        match &self.wavelengths {
+            None => rgb,
+            Some(wavelengths) => wavelengths.upsample(rgb),
+        }
+    }
+}
 
+// Both normals face the incoming ray. `normal` is the shading normal, which materials may perturb, while
+// `geometric_normal` is the true surface normal. dpdu and dpdv span the tangent plane along the texture coordinates.
+#[derive(Clone)]
 pub struct HitRecord {
     pub p: Vec3,
     pub normal: Vec3,
+    pub geometric_normal: Vec3,
     pub material: Rc<dyn Material>,
     pub t: f32,
     pub front_face: bool,
+    pub uv: (f32, f32),
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    pub object_id: u32,
+    pub material_id: u32,
+    pub medium_interface: Option<MediumInterface>, // None where the surface does not bound a medium
 }
 impl HitRecord {
     pub fn new(p: Vec3, normal: Vec3, material: Rc<dyn Material>, t: f32, front_face: bool) -> HitRecord {
-        HitRecord { p, normal, material, t, front_face }
+        HitRecord {
+            p,
+            normal,
+            geometric_normal: normal,
+            material,
+            t,
+            front_face,
+            uv: (0.0, 0.0),
+            dpdu: Vec3::zero(),
+            dpdv: Vec3::zero(),
+            object_id: 0,
+            material_id: 0,
+            medium_interface: None,
+        }
+    }
+    // unit tangent orthogonal to the shading normal, falling back to an arbitrary one where dpdu vanishes e.g. poles
+    pub fn tangent(&self) -> Vec3 {
+        let tangent = self.dpdu - self.normal * self.normal.dot(self.dpdu);
+        match tangent.length2() > 1e-12 {
+            true => tangent.normalize(),
+            false => self.normal.onb().0,
+        }
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy)]
 pub struct AABB {
     x: (f32, f32),
@@ -59,7 +124,22 @@ impl AABB {
             z: (a.z.0.min(b.z.0), a.z.1.max(b.z.1)),
         }
     }
+    // smallest box around the points
+    pub fn around(points: impl IntoIterator<Item = Vec3>) -> AABB {
+        points
+            .into_iter()
+            .fold(AABB::empty(), |bbox, p| AABB::from_aabb(bbox, AABB::from_vec3(p, p)))
+    }
+    // centre and radius of a sphere enclosing the box
+    pub fn bounding_sphere(&self) -> (Vec3, f32) {
+        let min = Vec3(self.x.0, self.y.0, self.z.0);
+        let max = Vec3(self.x.1, self.y.1, self.z.1);
+        let center = (min + max) * 0.5;
+        (center, (max - center).length())
+    }
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        #[cfg(feature = "traversal-counts")]
+        AABB_TESTS.with(|tests| tests.set(tests.get() + 1));
         let mut t_min = t_min;
         let mut t_max = t_max;
 
@@ -97,9 +177,36 @@ impl Index<usize> for AABB {
     }
 }
 
+// Work done finding hits since the last call to take_traversal_counts: the number of bounding boxes tested, and of
+// BVH nodes whose box the ray hit so that their children were searched. Only counted with the traversal-counts
+// feature, zero otherwise.
+#[cfg(feature = "traversal-counts")]
+thread_local! {
+    static AABB_TESTS: Cell<u32> = const { Cell::new(0) };
+    static BVH_VISITS: Cell<u32> = const { Cell::new(0) };
+}
+#[derive(Default)]
+pub struct TraversalCounts {
+    pub aabb_tests: u32,
+    pub bvh_visits: u32,
+}
+#[cfg(feature = "traversal-counts")]
+pub fn take_traversal_counts() -> TraversalCounts {
+    TraversalCounts {
+        aabb_tests: AABB_TESTS.with(|tests| tests.replace(0)),
+        bvh_visits: BVH_VISITS.with(|visits| visits.replace(0)),
+    }
+}
+#[cfg(not(feature = "traversal-counts"))]
+pub fn take_traversal_counts() -> TraversalCounts {
+    TraversalCounts::default()
+}
+
 pub trait Hittable {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
     fn bbox(&self) -> AABB;
+    // numbers the objects within and their materials, see SceneIds
+    fn assign_ids(&self, _ids: &mut SceneIds) {}
 }
 
 pub struct HittableList {
@@ -124,6 +231,11 @@ impl Hittable for HittableList {
     fn bbox(&self) -> AABB {
         self.bbox
     }
+    fn assign_ids(&self, ids: &mut SceneIds) {
+        for object in &self.objects {
+            object.assign_ids(ids);
+        }
+    }
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
         let mut t_max = t_max;
         let mut rec = None;
@@ -142,19 +254,42 @@ impl Hittable for HittableList {
     }
 }
 
+// IDs of objects and materials for the ID AOVs, given when the scene is built. They are numbered from 1 in the order
+// the objects of the scene are visited, so that they are the same in every render of it. 0 is left for the background.
+#[derive(Default)]
+pub struct SceneIds {
+    objects: u32,
+    materials: HashMap<*const u8, u32>,
+}
+impl SceneIds {
+    fn object(&mut self) -> u32 {
+        self.objects += 1;
+        self.objects
+    }
+    // materials shared by several objects keep one ID
+    fn material(&mut self, material: &Rc<dyn Material>) -> u32 {
+        let next = self.materials.len() as u32 + 1;
+        *self.materials.entry(Rc::as_ptr(material) as *const u8).or_insert(next)
+    }
+}
+
 pub struct Sphere {
     pub center: Vec3,
     pub radius: f32,
     pub material: Rc<dyn Material>,
     bbox: AABB,
+    id: Cell<u32>, // 0 until the scene assigns IDs
+    material_id: Cell<u32>,
 }
 impl Sphere {
     pub fn new(center: Vec3, radius: f32, material: Rc<dyn Material>) -> Sphere {
         Sphere {
             center,
             radius,
-            material,
             bbox: AABB::from_vec3(center - radius, center + radius),
+            id: Cell::new(0),
+            material_id: Cell::new(0),
+            material,
         }
     }
 }
@@ -162,6 +297,13 @@ impl Hittable for Sphere {
     fn bbox(&self) -> AABB {
         self.bbox
     }
+    // once, also where the sphere is in the scene more than once
+    fn assign_ids(&self, ids: &mut SceneIds) {
+        if self.id.get() == 0 {
+            self.id.set(ids.object());
+            self.material_id.set(ids.material(&self.material));
+        }
+    }
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
         let oc = ray.origin - self.center;
         let a = ray.direction.length2();
@@ -186,7 +328,7 @@ impl Hittable for Sphere {
         let outward_normal = (p - self.center) / self.radius;
         let front_face = ray.direction.dot(outward_normal) < 0.0;
 
-        Some(HitRecord::new(
+        let mut rec = HitRecord::new(
             p,
             match front_face {
                 true => outward_normal,
@@ -195,7 +337,105 @@ impl Hittable for Sphere {
             self.material.clone(),
             root,
             front_face,
-        ))
+        );
+        rec.object_id = self.id.get();
+        rec.material_id = self.material_id.get();
+
+        // u goes around the y axis starting from -x, v goes from the bottom pole to the top pole
+        let Vec3(x, y, z) = outward_normal;
+        let phi = f32::atan2(-z, x) + PI;
+        let theta = (-y).clamp(-1.0, 1.0).acos();
+        let rho = (x * x + z * z).sqrt().max(1e-6);
+        rec.uv = (phi / (2.0 * PI), theta / PI);
+        rec.dpdu = 2.0 * PI * self.radius * Vec3(z, 0.0, -x);
+        rec.dpdv = PI * self.radius * Vec3(-y * x / rho, rho, -y * z / rho);
+        Some(rec)
+    }
+}
+
+pub enum AlphaMode {
+    Threshold(f32), // opaque where alpha >= threshold
+    Stochastic,     // opaque with probability alpha
+}
+
+// Cutout mask e.g. for foliage and fences. Transparent hits are skipped and the search continues behind them.
+pub struct AlphaMasked {
+    object: Rc<dyn Hittable>,
+    alpha: Rc<dyn Texture>,
+    mode: AlphaMode,
+}
+impl AlphaMasked {
+    pub fn new(object: Rc<dyn Hittable>, alpha: Rc<dyn Texture>, mode: AlphaMode) -> AlphaMasked {
+        AlphaMasked { object, alpha, mode }
+    }
+}
+// Deterministic uniform number in [0, 1) from a ray and hit distance, so that tracing the same ray again sees the
+// same stochastic mask. Other rays to the same point, e.g. shadow rays, get independent values, which is still
+// correct on average.
+fn hash_float(ray: &Ray, t: f32) -> f32 {
+    let values = [
+        ray.origin.0,
+        ray.origin.1,
+        ray.origin.2,
+        ray.direction.0,
+        ray.direction.1,
+        ray.direction.2,
+        t,
+    ];
+    let mut h = 0x9e3779b97f4a7c15_u64;
+    for value in values {
+        h ^= value.to_bits() as u64;
+        h = h.wrapping_mul(0xbf58476d1ce4e5b9);
+        h ^= h >> 31;
+    }
+    (h >> 40) as f32 / (1 << 24) as f32
+}
+impl Hittable for AlphaMasked {
+    fn bbox(&self) -> AABB {
+        self.object.bbox()
+    }
+    fn assign_ids(&self, ids: &mut SceneIds) {
+        self.object.assign_ids(ids);
+    }
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut t_min = t_min;
+        loop {
+            let rec = self.object.hit(ray, t_min, t_max)?;
+            let alpha = self.alpha.value(rec.uv, rec.p).luminance();
+            let opaque = match self.mode {
+                AlphaMode::Threshold(threshold) => alpha >= threshold,
+                AlphaMode::Stochastic => alpha > hash_float(ray, rec.t),
+            };
+            if opaque {
+                return Some(rec);
+            }
+            t_min = rec.t;
+        }
+    }
+}
+
+// Object whose surface bounds participating media, see MediumInterface. With the Interface material it is only a
+// boundary, e.g. of a volume of fog, with any other material the medium fills the object, e.g. smoke inside glass.
+pub struct MediumBoundary {
+    object: Rc<dyn Hittable>,
+    interface: MediumInterface,
+}
+impl MediumBoundary {
+    pub fn new(object: Rc<dyn Hittable>, interface: MediumInterface) -> MediumBoundary {
+        MediumBoundary { object, interface }
+    }
+}
+impl Hittable for MediumBoundary {
+    fn bbox(&self) -> AABB {
+        self.object.bbox()
+    }
+    fn assign_ids(&self, ids: &mut SceneIds) {
+        self.object.assign_ids(ids);
+    }
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut rec = self.object.hit(ray, t_min, t_max)?;
+        rec.medium_interface = Some(self.interface.clone());
+        Some(rec)
     }
 }
 
@@ -269,10 +509,16 @@ impl Hittable for BVHNode {
     fn bbox(&self) -> AABB {
         self.bbox
     }
+    fn assign_ids(&self, ids: &mut SceneIds) {
+        self.left.assign_ids(ids);
+        self.right.assign_ids(ids);
+    }
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
         if !self.bbox.hit(ray, t_min, t_max) {
             return None;
         }
+        #[cfg(feature = "traversal-counts")]
+        BVH_VISITS.with(|visits| visits.set(visits.get() + 1));
         match self.left.hit(ray, t_min, t_max) {
             None => self.right.hit(ray, t_min, t_max),
             Some(left_rec) => match self.right.hit(ray, t_min, left_rec.t) {
@@ -0,0 +1,76 @@
+// A minimal reader for binary Netpbm images (PGM P5 and PPM P6, 8-bit). Values are returned in [0, 1].
+
+use std::fs;
+use std::io;
+
+use crate::vec3::Vec3;
+
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Vec3>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Image {
+    pub fn read_pnm(path: &str) -> io::Result<Image> {
+        let data = fs::read(path)?;
+
+        // header is 4 whitespace separated tokens, comments start with #
+        let mut tokens = Vec::new();
+        let mut pos = 0;
+        while tokens.len() < 4 {
+            while pos < data.len() && (data[pos].is_ascii_whitespace() || data[pos] == b'#') {
+                if data[pos] == b'#' {
+                    while pos < data.len() && data[pos] != b'\n' {
+                        pos += 1;
+                    }
+                }
+                pos += 1;
+            }
+            let start = pos;
+            while pos < data.len() && !data[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if start == pos {
+                return Err(invalid("Truncated header"));
+            }
+            tokens.push(String::from_utf8_lossy(&data[start..pos]).into_owned());
+        }
+        pos += 1; // single whitespace before the raster
+
+        let channels = match tokens[0].as_str() {
+            "P5" => 1,
+            "P6" => 3,
+            _ => return Err(invalid("Only binary PGM (P5) and PPM (P6) are supported")),
+        };
+        let parse = |s: &str| s.parse::<u32>().map_err(|_| invalid("Invalid header value"));
+        let (width, height, max_value) = (parse(&tokens[1])?, parse(&tokens[2])?, parse(&tokens[3])?);
+        if max_value > 255 {
+            return Err(invalid("Only 8-bit images are supported"));
+        }
+        let raster = data
+            .get(pos..pos + (width * height * channels) as usize)
+            .ok_or_else(|| invalid("Truncated raster"))?;
+
+        let scale = 1.0 / max_value as f32;
+        let pixels = raster
+            .chunks_exact(channels as usize)
+            .map(|c| match c {
+                [g] => Vec3::one() * (*g as f32 * scale),
+                _ => Vec3(c[0] as f32, c[1] as f32, c[2] as f32) * scale,
+            })
+            .collect();
+        Ok(Image { width, height, pixels })
+    }
+
+    // nearest neighbour lookup with wrap around. v = 0 is the bottom row.
+    pub fn get(&self, u: f32, v: f32) -> Vec3 {
+        let i = ((u.rem_euclid(1.0) * self.width as f32) as u32).min(self.width - 1);
+        let j = (((1.0 - v).rem_euclid(1.0) * self.height as f32) as u32).min(self.height - 1);
+        self.pixels[(j * self.width + i) as usize]
+    }
+}
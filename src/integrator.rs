@@ -0,0 +1,559 @@
+// Integrators compute the light arriving along camera rays, each with its own strategy for finding light paths
+
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+use crate::camera::Camera;
+use crate::hittable::{HitRecord, Hittable, Ray};
+use crate::medium::{next_medium, Medium};
+use crate::sampler::Sampler;
+use crate::scene::{Light, Scene};
+use crate::spectrum::Wavelengths;
+use crate::vec3::Vec3;
+
+// Radiance along a camera ray, split by the length of the light paths it came along so that direct and indirect
+// light can be clamped separately. Direct light reached the camera after at most one bounce. Light tracing may
+// also reach the camera elsewhere on the film, through splats.
+pub struct Radiance {
+    pub direct: Vec3,
+    pub indirect: Vec3,
+    pub splats: Vec<Splat>,
+    pub wavelengths: Option<Wavelengths>, // to convert to RGB with, when they changed along the path
+}
+// light reaching film coordinates s and t, see Camera::get_ray
+pub struct Splat {
+    pub s: f32,
+    pub t: f32,
+    pub radiance: Vec3,
+    pub bounces: u32,
+}
+impl Radiance {
+    pub fn zero() -> Radiance {
+        Radiance {
+            direct: Vec3::zero(),
+            indirect: Vec3::zero(),
+            splats: Vec::new(),
+            wavelengths: None,
+        }
+    }
+    pub fn add(&mut self, bounces: u32, value: Vec3) {
+        match bounces <= 1 {
+            true => self.direct = self.direct + value,
+            false => self.indirect = self.indirect + value,
+        }
+    }
+    // Called with the last ray of a path. Once dispersion has split up the wavelengths of a path only the hero
+    // wavelength is left, and all of the sample, splats included, is converted to RGB with it alone.
+    pub fn end_path(&mut self, ray: &Ray) {
+        if let Some(wavelengths) = ray.wavelengths.filter(Wavelengths::secondary_terminated) {
+            self.wavelengths = Some(wavelengths);
+        }
+    }
+}
+
+pub trait Integrator {
+    // radiance in the colour space of the ray, i.e. per wavelength for spectral rendering
+    fn li(&self, ray: Ray, scene: &Scene, camera: &dyn Camera, sampler: &mut dyn Sampler) -> Radiance;
+    // called before the first sample of every rendering pass, numbered from 1
+    fn start_pass(&self, _scene: &Scene, _camera: &dyn Camera, _pass: u32) {}
+}
+
+// so that paths of perfect specular bounces end too
+const MAX_SURVIVAL: f32 = 0.95;
+
+// Terminates the path at random with a probability that rises as its throughput falls, and weights survivors up to
+// compensate, which keeps the estimate unbiased. Returns false when the path ends.
+pub fn russian_roulette(throughput: &mut Vec3, sampler: &mut dyn Sampler) -> bool {
+    let survival = throughput.0.max(throughput.1).max(throughput.2).min(MAX_SURVIVAL);
+    if sampler.get_1d() >= survival {
+        return false;
+    }
+    *throughput = *throughput / survival;
+    true
+}
+
+// weight of a sample from the strategy with pdf a, when the strategy with pdf b could have produced it too
+fn power_heuristic(a: f32, b: f32) -> f32 {
+    match a > 0.0 {
+        true => a * a / (a * a + b * b),
+        false => 0.0,
+    }
+}
+
+// materials that can not be evaluated are only ever followed with scatter
+pub fn is_specular(ray: &Ray, rec: &HitRecord) -> bool {
+    rec.material.eval(ray, rec, rec.normal).is_none()
+}
+
+// Solid angle pdf of scatter having chosen direction. None for specular materials and for the delta lobes of others,
+// e.g. the transmission of Principled, which light sampling can not find and must be followed like specular scatter.
+pub fn scatter_pdf(ray: &Ray, rec: &HitRecord, direction: Vec3) -> Option<f32> {
+    let (_, pdf) = rec.material.eval(ray, rec, direction.normalize())?;
+    Some(pdf).filter(|&pdf| pdf > 0.0)
+}
+
+// light emitted by what the ray hit, or by the background when it hit nothing
+pub fn emitted(scene: &Scene, ray: &Ray, rec: Option<&HitRecord>) -> Vec3 {
+    match rec {
+        None => ray.spectrum(scene.background(ray)),
+        Some(rec) => ray.spectrum(rec.material.emitted(ray, rec)),
+    }
+}
+
+// Light from a point sampled on the light that reaches rec unoccluded, times the BSDF. choice_pdf is the probability
+// the light was picked with. With mis the sample is weighted against the chance of scatter finding it.
+fn direct_light(
+    scene: &Scene,
+    light: &Light,
+    choice_pdf: f32,
+    ray: &Ray,
+    rec: &HitRecord,
+    sampler: &mut dyn Sampler,
+    mis: bool,
+) -> Vec3 {
+    let Some(sample) = light.sample(rec.p, sampler) else {
+        return Vec3::zero();
+    };
+    let Some((f, bsdf_pdf)) = rec.material.eval(ray, rec, sample.direction) else {
+        return Vec3::zero();
+    };
+    if f.0.max(f.1).max(f.2) <= 0.0 {
+        return Vec3::zero();
+    }
+    // boundaries of media cast no shadows, as they are invisible to camera rays too
+    let shadow = ray.spawn(rec, sample.direction);
+    if transmittance(scene, shadow, sample.distance * (1.0 - 1e-3), None).0 <= 0.0 {
+        return Vec3::zero();
+    }
+    let light_pdf = sample.pdf * choice_pdf;
+    let weight = match mis {
+        true => power_heuristic(light_pdf, bsdf_pdf),
+        false => 1.0,
+    };
+    ray.spectrum(f) * ray.spectrum(sample.radiance) * (weight / light_pdf)
+}
+
+// next event estimation with one light picked at random
+pub fn sample_one_light(scene: &Scene, ray: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler, mis: bool) -> Vec3 {
+    match scene.sample_light(sampler) {
+        Some((light, choice_pdf)) => direct_light(scene, light, choice_pdf, ray, rec, sampler, mis),
+        None => Vec3::zero(),
+    }
+}
+
+// Path tracer. Paths continue until they leave the scene or are absorbed, and after min_depth bounces Russian
+// roulette ends them. max_depth only guards against endless paths, e.g. between perfect mirrors.
+pub struct PathIntegrator {
+    max_depth: u32,
+    min_depth: u32,
+    nee: bool,
+}
+impl PathIntegrator {
+    // follows scatter only, light is found when paths happen to hit an emitter or leave the scene
+    pub fn naive(max_depth: u32, min_depth: u32) -> PathIntegrator {
+        PathIntegrator { max_depth, min_depth, nee: false }
+    }
+    // samples a light at every bounce as well (next event estimation), combined with the light scatter finds by
+    // multiple importance sampling
+    pub fn mis(max_depth: u32, min_depth: u32) -> PathIntegrator {
+        PathIntegrator { max_depth, min_depth, nee: true }
+    }
+}
+impl Integrator for PathIntegrator {
+    fn li(&self, mut ray: Ray, scene: &Scene, _camera: &dyn Camera, sampler: &mut dyn Sampler) -> Radiance {
+        let mut radiance = Radiance::zero();
+        let mut throughput = Vec3::one();
+        // where the ray was scattered from and the pdf of its direction, None when light sampling could not have
+        // found the light it hits: from the camera, after specular scatter, or without NEE
+        let mut scattered: Option<(Vec3, f32)> = None;
+        for bounce in 0..self.max_depth {
+            let rec = scene.objects.hit(&ray, 0.001, f32::INFINITY);
+            let emitted = emitted(scene, &ray, rec.as_ref());
+            if emitted.0.max(emitted.1).max(emitted.2) > 0.0 {
+                let weight = match scattered {
+                    Some((p, pdf)) => {
+                        power_heuristic(pdf, scene.light_pdf(p, ray.direction, rec.as_ref().map(|rec| rec.p)))
+                    }
+                    None => 1.0,
+                };
+                radiance.add(bounce, throughput * emitted * weight);
+            }
+            let Some(rec) = rec else {
+                break;
+            };
+
+            if self.nee {
+                radiance.add(
+                    bounce + 1,
+                    throughput * sample_one_light(scene, &ray, &rec, sampler, true),
+                );
+            }
+            let Some((direction, color)) = rec.material.scatter(&ray, &rec, sampler) else {
+                break;
+            };
+            scattered = match self.nee {
+                true => scatter_pdf(&ray, &rec, direction).map(|pdf| (rec.p, pdf)),
+                false => None,
+            };
+            throughput = throughput * ray.spectrum(color);
+
+            if bounce + 1 >= self.min_depth && !russian_roulette(&mut throughput, sampler) {
+                break;
+            }
+            ray = ray.spawn(&rec, direction);
+        }
+        radiance.end_path(&ray);
+        radiance
+    }
+}
+
+// First surface along the ray that is more than a boundary between media, for views of the scene's surfaces such as
+// AOVs. Its t is along the ray, past any boundaries.
+pub fn first_surface(scene: &Scene, ray: &Ray) -> Option<HitRecord> {
+    let mut t_min = 0.001;
+    loop {
+        let rec = scene.objects.hit(ray, t_min, f32::INFINITY)?;
+        if !rec.material.is_interface() {
+            return Some(rec);
+        }
+        t_min = rec.t + 0.001;
+    }
+}
+
+// where light scatters: off a surface, or at a point in a medium
+enum Scattering<'a> {
+    Surface(&'a HitRecord),
+    Medium(&'a Medium, Vec3),
+}
+
+// Fraction of light passing from the origin of ray to distance along it, starting in medium. Boundaries of media
+// without a surface of their own are passed through, anything else blocks the light. Media end where the scene does,
+// so light from the sky is only attenuated up to the last surface on the way.
+pub fn transmittance(scene: &Scene, mut ray: Ray, mut distance: f32, mut medium: Option<Rc<Medium>>) -> Vec3 {
+    let mut transmittance = Vec3::one();
+    loop {
+        let rec = scene.objects.hit(&ray, 0.001, distance);
+        let t = rec.as_ref().map_or(distance, |rec| rec.t);
+        if let Some(medium) = medium.as_ref().filter(|_| t.is_finite()) {
+            transmittance = transmittance * medium.transmittance(&ray, t);
+        }
+        let Some(rec) = rec else {
+            return transmittance;
+        };
+        if !rec.material.is_interface() {
+            return Vec3::zero();
+        }
+        medium = next_medium(&rec, ray.direction, &medium);
+        distance -= rec.t;
+        ray = ray.spawn(&rec, ray.direction);
+    }
+}
+
+// Light sampling as in direct_light with MIS, attenuated by the media along the shadow ray
+fn direct_light_through_media(
+    scene: &Scene,
+    ray: &Ray,
+    at: Scattering,
+    medium: &Option<Rc<Medium>>,
+    sampler: &mut dyn Sampler,
+) -> Vec3 {
+    let Some((light, choice_pdf)) = scene.sample_light(sampler) else {
+        return Vec3::zero();
+    };
+    let p = match at {
+        Scattering::Surface(rec) => rec.p,
+        Scattering::Medium(_, p) => p,
+    };
+    let Some(sample) = light.sample(p, sampler) else {
+        return Vec3::zero();
+    };
+    let (f, scatter_pdf, shadow, shadow_medium) = match at {
+        Scattering::Surface(rec) => {
+            let Some((f, pdf)) = rec.material.eval(ray, rec, sample.direction) else {
+                return Vec3::zero();
+            };
+            let shadow_medium = next_medium(rec, sample.direction, medium);
+            (ray.spectrum(f), pdf, ray.spawn(rec, sample.direction), shadow_medium)
+        }
+        Scattering::Medium(scattering, p) => {
+            let phase = scattering.phase(ray.direction.normalize(), sample.direction);
+            let shadow = Ray { origin: p, direction: sample.direction, wavelengths: ray.wavelengths };
+            (Vec3::one() * phase, phase, shadow, medium.clone())
+        }
+    };
+    if f.0.max(f.1).max(f.2) <= 0.0 {
+        return Vec3::zero();
+    }
+    let transmittance = transmittance(scene, shadow, sample.distance * (1.0 - 1e-3), shadow_medium);
+    let light_pdf = sample.pdf * choice_pdf;
+    f * transmittance * ray.spectrum(sample.radiance) * (power_heuristic(light_pdf, scatter_pdf) / light_pdf)
+}
+
+// Path tracer with MIS through participating media. The path keeps track of the medium it is in, which changes where
+// it passes through the surface of a MediumBoundary. In a medium, the distance to where light scatters is sampled
+// before the next surface, and there the phase function takes the place of the BSDF. Crossing a boundary without a
+// surface of its own is not a bounce. Rays that leave the scene see the sky as it is, also from a medium without
+// bounds, which would otherwise swallow all light from the sky.
+pub struct VolumePathIntegrator {
+    max_depth: u32,
+    min_depth: u32,
+}
+impl VolumePathIntegrator {
+    pub fn new(max_depth: u32, min_depth: u32) -> VolumePathIntegrator {
+        VolumePathIntegrator { max_depth, min_depth }
+    }
+}
+impl Integrator for VolumePathIntegrator {
+    fn li(&self, mut ray: Ray, scene: &Scene, _camera: &dyn Camera, sampler: &mut dyn Sampler) -> Radiance {
+        let mut radiance = Radiance::zero();
+        let mut throughput = Vec3::one();
+        let mut medium = scene.medium.clone();
+        // as in PathIntegrator, the point scattered from and the pdf of the direction
+        let mut scattered: Option<(Vec3, f32)> = None;
+        let mut bounce = 0;
+        while bounce < self.max_depth {
+            let rec = scene.objects.hit(&ray, 0.001, f32::INFINITY);
+            if let (Some(current), Some(hit)) = (medium.clone(), &rec) {
+                let sample = current.sample(&ray, hit.t, sampler);
+                throughput = throughput * sample.weight;
+                if let Some(t) = sample.scattered {
+                    let p = ray.at(t);
+                    let direct =
+                        direct_light_through_media(scene, &ray, Scattering::Medium(&current, p), &medium, sampler);
+                    radiance.add(bounce + 1, throughput * direct);
+
+                    let wo = ray.direction.normalize();
+                    let wi = current.sample_phase(wo, sampler);
+                    scattered = Some((p, current.phase(wo, wi)));
+                    if bounce + 1 >= self.min_depth && !russian_roulette(&mut throughput, sampler) {
+                        break;
+                    }
+                    ray = Ray { origin: p, direction: wi, wavelengths: ray.wavelengths };
+                    bounce += 1;
+                    continue;
+                }
+            }
+
+            let emitted = emitted(scene, &ray, rec.as_ref());
+            if emitted.0.max(emitted.1).max(emitted.2) > 0.0 {
+                let weight = match scattered {
+                    Some((p, pdf)) => {
+                        power_heuristic(pdf, scene.light_pdf(p, ray.direction, rec.as_ref().map(|rec| rec.p)))
+                    }
+                    None => 1.0,
+                };
+                radiance.add(bounce, throughput * emitted * weight);
+            }
+            let Some(rec) = rec else {
+                break;
+            };
+            if rec.material.is_interface() {
+                medium = next_medium(&rec, ray.direction, &medium);
+                ray = ray.spawn(&rec, ray.direction);
+                continue;
+            }
+
+            let direct = direct_light_through_media(scene, &ray, Scattering::Surface(&rec), &medium, sampler);
+            radiance.add(bounce + 1, throughput * direct);
+            let Some((direction, color)) = rec.material.scatter(&ray, &rec, sampler) else {
+                break;
+            };
+            scattered = scatter_pdf(&ray, &rec, direction).map(|pdf| (rec.p, pdf));
+            throughput = throughput * ray.spectrum(color);
+            if bounce + 1 >= self.min_depth && !russian_roulette(&mut throughput, sampler) {
+                break;
+            }
+            medium = next_medium(&rec, direction, &medium);
+            ray = ray.spawn(&rec, direction);
+            bounce += 1;
+        }
+        radiance.end_path(&ray);
+        radiance
+    }
+}
+
+// Light reaching the first diffuse or glossy surface directly from emitters, by light sampling and scatter combined
+// with MIS. Specular surfaces and delta lobes on the way there are followed.
+pub struct DirectLightingIntegrator {
+    max_depth: u32,
+}
+impl DirectLightingIntegrator {
+    pub fn new(max_depth: u32) -> DirectLightingIntegrator {
+        DirectLightingIntegrator { max_depth }
+    }
+}
+impl Integrator for DirectLightingIntegrator {
+    fn li(&self, mut ray: Ray, scene: &Scene, _camera: &dyn Camera, sampler: &mut dyn Sampler) -> Radiance {
+        let mut radiance = Radiance::zero();
+        let mut throughput = Vec3::one();
+        for bounce in 0..self.max_depth {
+            let rec = scene.objects.hit(&ray, 0.001, f32::INFINITY);
+            radiance.add(bounce, throughput * emitted(scene, &ray, rec.as_ref()));
+            let Some(rec) = rec else {
+                break;
+            };
+            if !is_specular(&ray, &rec) {
+                radiance.add(
+                    bounce + 1,
+                    throughput * sample_one_light(scene, &ray, &rec, sampler, true),
+                );
+            }
+            let Some((direction, color)) = rec.material.scatter(&ray, &rec, sampler) else {
+                break;
+            };
+            let Some(pdf) = scatter_pdf(&ray, &rec, direction) else {
+                throughput = throughput * ray.spectrum(color);
+                ray = ray.spawn(&rec, direction);
+                continue;
+            };
+            let next = ray.spawn(&rec, direction);
+            let hit = scene.objects.hit(&next, 0.001, f32::INFINITY);
+            let weight = power_heuristic(
+                pdf,
+                scene.light_pdf(rec.p, next.direction, hit.as_ref().map(|hit| hit.p)),
+            );
+            let emitted = emitted(scene, &next, hit.as_ref());
+            radiance.add(bounce + 1, throughput * ray.spectrum(color) * emitted * weight);
+            break;
+        }
+        radiance.end_path(&ray);
+        radiance
+    }
+}
+
+// Ambient occlusion: the cosine weighted fraction of the hemisphere above the first hit that is open within
+// distance. Rays leaving the scene see white.
+pub struct AmbientOcclusionIntegrator {
+    distance: f32,
+}
+impl AmbientOcclusionIntegrator {
+    pub fn new(distance: f32) -> AmbientOcclusionIntegrator {
+        AmbientOcclusionIntegrator { distance }
+    }
+}
+impl Integrator for AmbientOcclusionIntegrator {
+    fn li(&self, ray: Ray, scene: &Scene, _camera: &dyn Camera, sampler: &mut dyn Sampler) -> Radiance {
+        let mut radiance = Radiance::zero();
+        let Some(rec) = scene.objects.hit(&ray, 0.001, f32::INFINITY) else {
+            radiance.add(0, ray.spectrum(Vec3::one()));
+            return radiance;
+        };
+        let normal = rec.material.shading_normal(&rec);
+        let (tangent, bitangent) = normal.onb();
+        let (u1, u2) = sampler.get_2d();
+        let (r, phi) = (u1.sqrt(), 2.0 * PI * u2);
+        let direction = tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normal * (1.0 - u1).sqrt();
+        let occluder = scene.objects.hit(&ray.spawn(&rec, direction), 0.001, self.distance);
+        if occluder.is_none() {
+            radiance.add(1, ray.spectrum(Vec3::one()));
+        }
+        radiance
+    }
+}
+
+// Whitted style ray tracing: specular reflection and refraction, including delta lobes of other materials, are
+// followed, diffuse and glossy surfaces are lit directly by every light, without indirect light
+pub struct WhittedIntegrator {
+    max_depth: u32,
+}
+impl WhittedIntegrator {
+    pub fn new(max_depth: u32) -> WhittedIntegrator {
+        WhittedIntegrator { max_depth }
+    }
+}
+impl Integrator for WhittedIntegrator {
+    fn li(&self, mut ray: Ray, scene: &Scene, _camera: &dyn Camera, sampler: &mut dyn Sampler) -> Radiance {
+        let mut radiance = Radiance::zero();
+        let mut throughput = Vec3::one();
+        for bounce in 0..self.max_depth {
+            let rec = scene.objects.hit(&ray, 0.001, f32::INFINITY);
+            radiance.add(bounce, throughput * emitted(scene, &ray, rec.as_ref()));
+            let Some(rec) = rec else {
+                break;
+            };
+            if !is_specular(&ray, &rec) {
+                for light in &scene.lights {
+                    let direct = direct_light(scene, light, 1.0, &ray, &rec, sampler, false);
+                    radiance.add(bounce + 1, throughput * direct);
+                }
+            }
+            let Some((direction, color)) = rec.material.scatter(&ray, &rec, sampler) else {
+                break;
+            };
+            if scatter_pdf(&ray, &rec, direction).is_some() {
+                break;
+            }
+            throughput = throughput * ray.spectrum(color);
+            ray = ray.spawn(&rec, direction);
+        }
+        radiance.end_path(&ray);
+        radiance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::bdpt::BdptIntegrator;
+    use crate::camera::OrthographicCamera;
+    use crate::hittable::{HittableList, Sphere};
+    use crate::material::Lambertian;
+    use crate::principled::{Principled, PrincipledParams};
+    use crate::sampler::IndependentSampler;
+
+    // average luminance of an image filled by a Principled glass sphere on a grey ground sphere under the sky
+    fn render_glass(integrator: &dyn Integrator, ground_radius: f32) -> f32 {
+        let glass: PrincipledParams = "base_color=0.9,0.9,0.9 roughness=0.05 transmission=1 ior=1.45"
+            .parse()
+            .unwrap();
+        let mut objects = HittableList::new();
+        objects.push(Rc::new(Sphere::new(
+            Vec3(0.0, 1.0, 0.0),
+            1.0,
+            Rc::new(Principled::new(glass)),
+        )));
+        objects.push(Rc::new(Sphere::new(
+            Vec3(0.0, -ground_radius, 0.0),
+            ground_radius,
+            Rc::new(Lambertian::new(Vec3::one() * 0.5)),
+        )));
+        let scene = Scene::new(objects, vec![Light::Sky { scale: 1.0 }]);
+        let up = Vec3(0.0, 1.0, 0.0);
+        let camera = OrthographicCamera::new(1.0, Vec3(0.0, 1.0, 5.0), Vec3(0.0, 1.0, 0.0), up, 2.0);
+
+        let (size, spp) = (32, 16);
+        let mut sampler = IndependentSampler::new();
+        let mut sum = 0.0;
+        for pixel in 0..size * size {
+            for index in 0..spp {
+                sampler.start_pixel_sample((pixel % size, pixel / size), index);
+                let (dx, dy) = sampler.get_2d();
+                let s = ((pixel % size) as f32 + dx) / size as f32;
+                let t = ((pixel / size) as f32 + dy) / size as f32;
+                let (ray, weight) = camera.get_ray(s, t, &mut sampler).unwrap();
+                let radiance = integrator.li(ray, &scene, &camera, &mut sampler);
+                sum += ((radiance.direct + radiance.indirect) * weight).luminance();
+            }
+        }
+        sum / (size * size * spp) as f32
+    }
+
+    // light sampling can not find light through delta lobes, so scatter must not be weighted against it there
+    #[test]
+    fn path_and_mis_agree_through_principled_glass() {
+        let path = render_glass(&PathIntegrator::naive(16, 5), 1000.0);
+        let mis = render_glass(&PathIntegrator::mis(16, 5), 1000.0);
+        assert!((path - mis).abs() < 0.02 * path, "path {path}, mis {mis}");
+    }
+
+    // Light subpaths from the sky add strategies, which the MIS weights must account for. They start from a disk as
+    // wide as the scene, which a small ground keeps from spreading them too thin to take part.
+    #[test]
+    fn path_and_bdpt_agree_under_the_sky() {
+        let path = render_glass(&PathIntegrator::mis(16, 5), 2.0);
+        let bdpt = render_glass(&BdptIntegrator::new(16, 5), 2.0);
+        assert!((path - bdpt).abs() < 0.02 * path, "path {path}, bdpt {bdpt}");
+    }
+}
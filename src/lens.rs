@@ -0,0 +1,361 @@
+// Camera tracing rays through a multi-element spherical lens, following pbrt's RealisticCamera. Lens prescriptions
+// use the same table format as pbrt: one surface per line from the front (scene side) to the back (film side), with
+// curvature radius, thickness, index of refraction and aperture diameter in mm. A radius of 0 is the aperture stop.
+// The IOR is the one of the medium behind the surface. Scene units are metres.
+//
+// Lens space has the film at z = 0 and the lens in front of it along +z.
+
+use crate::camera::Camera;
+use crate::hittable::Ray;
+use crate::sampler::Sampler;
+use crate::vec3::Vec3;
+
+// D-GAUSS F/2 22deg HFOV, US patent 2,673,491 (Tronnier), scaled to 50 mm
+pub const DOUBLE_GAUSS_50MM: &str = "
+    29.475   3.76   1.67   25.2
+    84.83    0.12   1      25.2
+    19.275   4.025  1.67   23
+    40.77    3.275  1.699  23
+    12.75    5.705  1      18
+    0        4.5    0      17.1
+    -14.495  1.18   1.603  17
+    40.77    6.065  1.658  20
+    -20.385  0.19   1      20
+    437.065  3.22   1.717  20
+    -39.73   0      1      20
+";
+
+pub struct LensElement {
+    curvature_radius: f32,
+    thickness: f32,
+    eta: f32,
+    aperture_radius: f32,
+}
+
+impl LensElement {
+    pub fn parse_table(table: &str) -> Result<Vec<LensElement>, String> {
+        let mut elements = Vec::new();
+        for line in table
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        {
+            let values = line
+                .split_whitespace()
+                .map(|x| {
+                    x.parse::<f32>()
+                        .map_err(|e| format!("Invalid lens table line '{line}': {e}"))
+                })
+                .collect::<Result<Vec<f32>, String>>()?;
+            match values[..] {
+                [radius, thickness, eta, diameter] => elements.push(LensElement {
+                    curvature_radius: radius * 0.001,
+                    thickness: thickness * 0.001,
+                    eta,
+                    aperture_radius: diameter * 0.001 / 2.0,
+                }),
+                _ => return Err(format!("Expected 4 values per lens table line, found '{line}'")),
+            }
+        }
+        match elements.is_empty() {
+            true => Err(String::from("Empty lens table")),
+            false => Ok(elements),
+        }
+    }
+    fn is_stop(&self) -> bool {
+        self.curvature_radius == 0.0
+    }
+}
+
+// refraction with eta = eta_i / eta_t. wi points away from the surface, on the same side as n.
+fn refract(wi: Vec3, n: Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = n.dot(wi);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(eta * -wi + (eta * cos_i - cos_t) * n)
+}
+
+fn intersect_spherical(radius: f32, z_center: f32, o: Vec3, d: Vec3) -> Option<(f32, Vec3)> {
+    let oc = o - Vec3(0.0, 0.0, z_center);
+    let a = d.length2();
+    let half_b = oc.dot(d);
+    let c = oc.length2() - radius * radius;
+    let discriminant = half_b * half_b - a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let t0 = (-half_b - discriminant.sqrt()) / a;
+    let t1 = (-half_b + discriminant.sqrt()) / a;
+    // the part of the sphere used by the element depends on the direction of travel and the sign of the radius
+    let t = match (d.2 > 0.0) ^ (radius < 0.0) {
+        true => t0.min(t1),
+        false => t0.max(t1),
+    };
+    if t < 0.0 {
+        return None;
+    }
+    let n = (oc + t * d).normalize();
+    let n = match n.dot(d) > 0.0 {
+        true => -n,
+        false => n,
+    };
+    Some((t, n))
+}
+
+#[derive(Clone, Copy)]
+struct Bounds2 {
+    min: (f32, f32),
+    max: (f32, f32),
+}
+impl Bounds2 {
+    fn area(&self) -> f32 {
+        (self.max.0 - self.min.0) * (self.max.1 - self.min.1)
+    }
+    fn lerp(&self, u: f32, v: f32) -> (f32, f32) {
+        (
+            self.min.0 + u * (self.max.0 - self.min.0),
+            self.min.1 + v * (self.max.1 - self.min.1),
+        )
+    }
+    fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.min.0 && x <= self.max.0 && y >= self.min.1 && y <= self.max.1
+    }
+}
+
+fn radical_inverse_2(i: u32) -> f32 {
+    i.reverse_bits() as f32 / 4294967296.0
+}
+fn radical_inverse_3(mut i: u32) -> f32 {
+    let (mut result, mut scale) = (0.0, 1.0 / 3.0);
+    while i > 0 {
+        result += (i % 3) as f32 * scale;
+        i /= 3;
+        scale /= 3.0;
+    }
+    result
+}
+
+pub struct RealisticCamera {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    elements: Vec<LensElement>,
+    film_width: f32,
+    film_height: f32,
+    exit_pupil_bounds: Vec<Bounds2>,
+}
+
+impl RealisticCamera {
+    const FILM_WIDTH: f32 = 0.036;
+    const N_PUPIL_BOUNDS: usize = 32;
+    const N_PUPIL_SAMPLES: u32 = 4096;
+
+    // focus_distance is measured from the film
+    pub fn new(
+        aspect_ratio: f32,
+        look_from: Vec3,
+        look_at: Vec3,
+        vup: Vec3,
+        elements: Vec<LensElement>,
+        focus_distance: f32,
+    ) -> RealisticCamera {
+        let w = (look_from - look_at).normalize();
+        let u = vup.cross(w).normalize();
+        let v = w.cross(u);
+        let mut camera = RealisticCamera {
+            origin: look_from,
+            u,
+            v,
+            w,
+            elements,
+            film_width: RealisticCamera::FILM_WIDTH,
+            film_height: RealisticCamera::FILM_WIDTH / aspect_ratio,
+            exit_pupil_bounds: Vec::new(),
+        };
+        let film_distance = camera.focus_thick_lens(focus_distance);
+        camera.elements.last_mut().unwrap().thickness = film_distance;
+
+        let half_diagonal = 0.5 * camera.film_width.hypot(camera.film_height);
+        let n = RealisticCamera::N_PUPIL_BOUNDS;
+        camera.exit_pupil_bounds = (0..n)
+            .map(|i| {
+                camera.bound_exit_pupil(
+                    half_diagonal * i as f32 / n as f32,
+                    half_diagonal * (i + 1) as f32 / n as f32,
+                )
+            })
+            .collect();
+        camera
+    }
+
+    fn lens_rear_z(&self) -> f32 {
+        self.elements.last().unwrap().thickness
+    }
+    fn lens_front_z(&self) -> f32 {
+        self.elements.iter().map(|e| e.thickness).sum()
+    }
+    fn rear_element_radius(&self) -> f32 {
+        self.elements.last().unwrap().aperture_radius
+    }
+
+    // Both tracing functions take and return rays in lens space. Tracing happens in a space with z flipped, where
+    // the elements are at negative z.
+    fn trace_from_film(&self, o: Vec3, d: Vec3) -> Option<(Vec3, Vec3)> {
+        let (mut o, mut d) = (Vec3(o.0, o.1, -o.2), Vec3(d.0, d.1, -d.2));
+        let mut element_z = 0.0;
+        for (i, element) in self.elements.iter().enumerate().rev() {
+            element_z -= element.thickness;
+            let (t, n) = match element.is_stop() {
+                true if d.2 >= 0.0 => return None,
+                true => ((element_z - o.2) / d.2, Vec3::zero()),
+                false => intersect_spherical(element.curvature_radius, element_z + element.curvature_radius, o, d)?,
+            };
+            o = o + t * d;
+            if o.0 * o.0 + o.1 * o.1 > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+            if !element.is_stop() {
+                let eta_i = element.eta;
+                let eta_t = match i > 0 && self.elements[i - 1].eta != 0.0 {
+                    true => self.elements[i - 1].eta,
+                    false => 1.0,
+                };
+                d = refract(-d.normalize(), n, eta_i / eta_t)?;
+            }
+        }
+        Some((Vec3(o.0, o.1, -o.2), Vec3(d.0, d.1, -d.2)))
+    }
+
+    fn trace_from_scene(&self, o: Vec3, d: Vec3) -> Option<(Vec3, Vec3)> {
+        let (mut o, mut d) = (Vec3(o.0, o.1, -o.2), Vec3(d.0, d.1, -d.2));
+        let mut element_z = -self.lens_front_z();
+        for (i, element) in self.elements.iter().enumerate() {
+            let (t, n) = match element.is_stop() {
+                true => ((element_z - o.2) / d.2, Vec3::zero()),
+                false => intersect_spherical(element.curvature_radius, element_z + element.curvature_radius, o, d)?,
+            };
+            o = o + t * d;
+            if o.0 * o.0 + o.1 * o.1 > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+            if !element.is_stop() {
+                let eta_i = match i == 0 || self.elements[i - 1].eta == 0.0 {
+                    true => 1.0,
+                    false => self.elements[i - 1].eta,
+                };
+                let eta_t = match element.eta != 0.0 {
+                    true => element.eta,
+                    false => 1.0,
+                };
+                d = refract(-d.normalize(), n, eta_i / eta_t)?;
+            }
+            element_z += element.thickness;
+        }
+        Some((Vec3(o.0, o.1, -o.2), Vec3(d.0, d.1, -d.2)))
+    }
+
+    // principal plane and focal point along z of a paraxial ray entering at (o_in) and leaving as (o_out, d_out)
+    fn cardinal_points(o_in: Vec3, o_out: Vec3, d_out: Vec3) -> (f32, f32) {
+        let tf = -o_out.0 / d_out.0;
+        let tp = (o_in.0 - o_out.0) / d_out.0;
+        (-(o_out.2 + tp * d_out.2), -(o_out.2 + tf * d_out.2))
+    }
+
+    // principal plane and focal point on the film side, from a paraxial ray entering from the scene
+    fn film_side_cardinal_points(&self) -> (f32, f32) {
+        let x = 0.001 * self.film_width.hypot(self.film_height);
+        let scene_origin = Vec3(x, 0.0, self.lens_front_z() + 1.0);
+        let (o, d) = self
+            .trace_from_scene(scene_origin, Vec3(0.0, 0.0, -1.0))
+            .expect("Paraxial ray from the scene does not pass through the lens");
+        RealisticCamera::cardinal_points(scene_origin, o, d)
+    }
+
+    // thick lens approximation to find the film distance behind the rear element which focuses at focus_distance
+    fn focus_thick_lens(&self, focus_distance: f32) -> f32 {
+        let x = 0.001 * self.film_width.hypot(self.film_height);
+        let (pz0, fz0) = self.film_side_cardinal_points();
+
+        let film_origin = Vec3(x, 0.0, self.lens_rear_z() - 1.0);
+        let (o, d) = self
+            .trace_from_film(film_origin, Vec3(0.0, 0.0, 1.0))
+            .expect("Paraxial ray from the film does not pass through the lens");
+        let (pz1, _) = RealisticCamera::cardinal_points(film_origin, o, d);
+
+        let f = fz0 - pz0;
+        let z = -focus_distance;
+        let c = (pz1 - z - pz0) * (pz1 - z - 4.0 * f - pz0);
+        assert!(
+            c >= 0.0,
+            "Cannot focus at {focus_distance} m, it is too close to the lens"
+        );
+        let delta = 0.5 * (pz1 - z + pz0 - c.sqrt());
+        self.lens_rear_z() + delta
+    }
+
+    // bounding box on the rear element plane of rays from film points at radius r0..r1 that leave the lens
+    fn bound_exit_pupil(&self, r0: f32, r1: f32) -> Bounds2 {
+        let rear_radius = 1.5 * self.rear_element_radius();
+        let rear_bounds = Bounds2 { min: (-rear_radius, -rear_radius), max: (rear_radius, rear_radius) };
+        let mut bounds: Option<Bounds2> = None;
+
+        let n = RealisticCamera::N_PUPIL_SAMPLES;
+        for i in 0..n {
+            let film = Vec3(r0 + (i as f32 + 0.5) / n as f32 * (r1 - r0), 0.0, 0.0);
+            let (x, y) = rear_bounds.lerp(radical_inverse_2(i), radical_inverse_3(i));
+            let rear = Vec3(x, y, self.lens_rear_z());
+            let inside = bounds.is_some_and(|b| b.contains(x, y));
+            if inside || self.trace_from_film(film, rear - film).is_some() {
+                bounds = Some(match bounds {
+                    None => Bounds2 { min: (x, y), max: (x, y) },
+                    Some(b) => Bounds2 { min: (b.min.0.min(x), b.min.1.min(y)), max: (b.max.0.max(x), b.max.1.max(y)) },
+                });
+            }
+        }
+        match bounds {
+            None => rear_bounds,
+            Some(b) => {
+                let pad = 2.0 * 2.0 * rear_radius * 2.0_f32.sqrt() / (n as f32).sqrt();
+                Bounds2 { min: (b.min.0 - pad, b.min.1 - pad), max: (b.max.0 + pad, b.max.1 + pad) }
+            }
+        }
+    }
+}
+
+impl Camera for RealisticCamera {
+    fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<(Ray, f32)> {
+        // the image on the film is upside down
+        let film = Vec3(-(s - 0.5) * self.film_width, -(t - 0.5) * self.film_height, 0.0);
+
+        // sample the exit pupil bounds of this film radius, rotated to the film point's angle
+        let r_film = film.0.hypot(film.1);
+        let n = self.exit_pupil_bounds.len();
+        let index = ((r_film / (0.5 * self.film_width.hypot(self.film_height)) * n as f32) as usize).min(n - 1);
+        let bounds = self.exit_pupil_bounds[index];
+        let (u1, u2) = sampler.get_2d();
+        let (x, y) = bounds.lerp(u1, u2);
+        let (sin, cos) = match r_film > 0.0 {
+            true => (film.1 / r_film, film.0 / r_film),
+            false => (0.0, 1.0),
+        };
+        let rear = Vec3(cos * x - sin * y, sin * x + cos * y, self.lens_rear_z());
+
+        let direction = rear - film;
+        let (o, d) = self.trace_from_film(film, direction)?;
+        let cos_theta = direction.normalize().2;
+        let weight = cos_theta.powi(4) * bounds.area() / self.exit_pupil_bounds[0].area();
+
+        let to_world = |v: Vec3| v.0 * self.u + v.1 * self.v - v.2 * self.w;
+        Some((Ray::new(self.origin + to_world(o), to_world(d)), weight))
+    }
+    // effective focal length over the diameter of the aperture stop. None for prescriptions without a stop.
+    fn f_number(&self) -> Option<f32> {
+        let (pz, fz) = self.film_side_cardinal_points();
+        let stop = self.elements.iter().find(|e| e.is_stop())?;
+        Some((fz - pz) / (2.0 * stop.aperture_radius))
+    }
+}
@@ -1,17 +1,61 @@
+mod aov;
+mod bdpt;
 mod camera;
+mod checkpoint;
+mod debug;
+mod denoise;
+mod exr;
+mod film;
 mod hittable;
+mod image;
+mod integrator;
+mod lens;
 mod material;
+mod medium;
+mod mlt;
+mod options;
 mod pcg32;
+mod principled;
+mod sampler;
+mod scene;
+mod spectrum;
+mod sppm;
+mod texture;
 mod tiff;
 mod vec3;
+use std::fs;
 use std::rc::Rc;
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use camera::{Camera, Renderer};
-use hittable::{BVHNode, HittableList, Sphere};
-use material::{Dielectric, Lambertian, Material, Metal};
+use aov::{write_multilayer, write_separate};
+use bdpt::BdptIntegrator;
+use camera::{
+    stereo_eye, ApertureShape, Camera, EquirectangularCamera, Exposure, FisheyeCamera, OrthographicCamera,
+    PanoramaCamera, PerspectiveCamera, Renderer, StereoCamera, StereoLayout,
+};
+use checkpoint::Checkpoint;
+use debug::{DebugIntegrator, DebugMode};
+use denoise::{Denoiser, Frame};
+use exr::{write_exr, Channel};
+use film::{encode_linear_rgb8, encode_rgb8, CropWindow, Film, Filter};
+use hittable::{AlphaMasked, AlphaMode, BVHNode, HittableList, MediumBoundary, Sphere};
+use image::Image;
+use integrator::{
+    AmbientOcclusionIntegrator, DirectLightingIntegrator, Integrator, PathIntegrator, VolumePathIntegrator,
+    WhittedIntegrator,
+};
+use lens::{LensElement, RealisticCamera, DOUBLE_GAUSS_50MM};
+use material::{Coated, Dielectric, Interface, Ior, Lambertian, Material, Metal, NormalMapped, Perturbation};
+use medium::{Medium, MediumInterface};
+use mlt::MltIntegrator;
+use options::Options;
 use pcg32::PCG32;
+use principled::{Principled, PrincipledParams};
+use sampler::{BlueNoiseSampler, HaltonSampler, IndependentSampler, Sampler, SobolSampler, StratifiedSampler};
+use scene::{Light, Scene};
+use sppm::SppmIntegrator;
+use texture::{ImageTexture, Waves};
 use tiff::TiffFile;
 use vec3::Vec3;
 
@@ -63,29 +107,571 @@ fn generate_spheres(objects: &mut HittableList) {
     objects.push(bvh);
 }
 
-fn main() {
-    let aspect_ratio = 16.0 / 9.0;
-    let camera = Camera::new(
-        aspect_ratio,
-        Vec3(13.0, 2.0, 3.0),
-        Vec3(0.0, 0.0, 0.0),
+// glass and gem spheres with wavelength-dependent IOR. best viewed with --spectral
+fn generate_dispersion(objects: &mut HittableList) {
+    objects.push(Rc::new(Sphere::new(
+        Vec3(0.0, -1000.0, 0.0),
+        1000.0,
+        Rc::new(Lambertian::new(Vec3(0.5, 0.5, 0.5))),
+    )));
+    // BK7 crown glass
+    objects.push(Rc::new(Sphere::new(
+        Vec3(-4.0, 1.0, 0.0),
+        1.0,
+        Rc::new(Dielectric::with_ior(Ior::Cauchy(1.5046, 0.0042))),
+    )));
+    // SF10 dense flint glass
+    objects.push(Rc::new(Sphere::new(
+        Vec3(0.0, 1.0, 0.0),
+        1.0,
+        Rc::new(Dielectric::with_ior(Ior::Cauchy(1.728, 0.01342))),
+    )));
+    // diamond
+    objects.push(Rc::new(Sphere::new(
+        Vec3(4.0, 1.0, 0.0),
+        1.0,
+        Rc::new(Dielectric::with_ior(Ior::Sellmeier(
+            [0.3306, 4.3356, 0.0],
+            [0.030625, 0.011236, 0.0],
+        ))),
+    )));
+    for a in -11..11_i32 {
+        let color = match a.rem_euclid(3) {
+            0 => Vec3(0.8, 0.1, 0.1),
+            1 => Vec3(0.1, 0.8, 0.1),
+            _ => Vec3(0.1, 0.1, 0.8),
+        };
+        objects.push(Rc::new(Sphere::new(
+            Vec3(a as f32, 0.2, -4.0),
+            0.2,
+            Rc::new(Lambertian::new(color)),
+        )));
+    }
+}
+
+// principled materials, built from code and from asset-style parameter strings
+fn generate_principled(objects: &mut HittableList) {
+    objects.push(Rc::new(Sphere::new(
+        Vec3(0.0, -1000.0, 0.0),
+        1000.0,
+        Rc::new(Lambertian::new(Vec3(0.5, 0.5, 0.5))),
+    )));
+
+    let assets = [
+        "base_color=0.9,0.6,0.2 metallic=1 roughness=0.2",
+        "base_color=0.1,0.3,0.8 roughness=0.4 clearcoat=1 clearcoat_gloss=0.9",
+        "base_color=0.9,0.9,0.9 roughness=0.05 transmission=1 ior=1.45",
+        "base_color=0.9,0.5,0.4 roughness=0.6 subsurface=1 sheen=0.5",
+    ];
+    for (i, asset) in assets.iter().enumerate() {
+        let params: PrincipledParams = asset.parse().unwrap();
+        objects.push(Rc::new(Sphere::new(
+            Vec3(-4.5 + 3.0 * i as f32, 1.0, 0.0),
+            1.0,
+            Rc::new(Principled::new(params)),
+        )));
+    }
+    for i in 0..11 {
+        let params = PrincipledParams {
+            base_color: Vec3(0.8, 0.1, 0.1),
+            metallic: 0.5,
+            roughness: i as f32 / 10.0,
+            ..Default::default()
+        };
+        objects.push(Rc::new(Sphere::new(
+            Vec3(-5.0 + i as f32, 0.3, 3.0),
+            0.3,
+            Rc::new(Principled::new(params)),
+        )));
+    }
+}
+
+// clear coats over other materials: car paint, coated metal and varnished wood
+fn generate_layered(objects: &mut HittableList) {
+    objects.push(Rc::new(Sphere::new(
+        Vec3(0.0, -1000.0, 0.0),
+        1000.0,
+        Rc::new(Lambertian::new(Vec3(0.5, 0.5, 0.5))),
+    )));
+
+    let materials: [Rc<dyn Material>; 4] = [
+        Rc::new(Lambertian::new(Vec3(0.6, 0.05, 0.05))),
+        Rc::new(Coated::new(
+            Rc::new(Lambertian::new(Vec3(0.6, 0.05, 0.05))),
+            1.5,
+            0.01,
+            Vec3::zero(),
+        )),
+        Rc::new(Coated::new(
+            Rc::new(Metal::new(Vec3(0.8, 0.8, 0.8), 0.3)),
+            1.6,
+            0.05,
+            Vec3(0.0, 2.0, 6.0),
+        )),
+        Rc::new(Coated::new(
+            Rc::new(Lambertian::new(Vec3(0.5, 0.3, 0.15))),
+            1.5,
+            0.1,
+            Vec3(0.2, 1.0, 3.0),
+        )),
+    ];
+    for (i, material) in materials.into_iter().enumerate() {
+        objects.push(Rc::new(Sphere::new(
+            Vec3(-4.5 + 3.0 * i as f32, 1.0, 0.0),
+            1.0,
+            material,
+        )));
+    }
+}
+
+// bump mapped spheres, and an optional tangent space normal map (binary PPM) on the centre sphere
+fn generate_bumpy(objects: &mut HittableList, normal_map: &Option<String>) {
+    objects.push(Rc::new(Sphere::new(
+        Vec3(0.0, -1000.0, 0.0),
+        1000.0,
+        Rc::new(Lambertian::new(Vec3(0.5, 0.5, 0.5))),
+    )));
+
+    let waves = Rc::new(Waves::new((16.0, 8.0)));
+    objects.push(Rc::new(Sphere::new(
+        Vec3(-4.0, 1.0, 0.0),
+        1.0,
+        Rc::new(NormalMapped::new(
+            Rc::new(Lambertian::new(Vec3(0.4, 0.2, 0.1))),
+            Perturbation::Bump(waves.clone(), 0.02),
+        )),
+    )));
+    objects.push(Rc::new(Sphere::new(
+        Vec3(4.0, 1.0, 0.0),
+        1.0,
+        Rc::new(NormalMapped::new(
+            Rc::new(Metal::new(Vec3(0.7, 0.6, 0.5), 0.0)),
+            Perturbation::Bump(waves, 0.01),
+        )),
+    )));
+
+    let base = Rc::new(Lambertian::new(Vec3(0.7, 0.7, 0.7)));
+    let material: Rc<dyn Material> = match normal_map {
+        None => base,
+        Some(path) => {
+            let image = Image::read_pnm(path).unwrap_or_else(|e| panic!("Cannot read {path}: {e}"));
+            let texture = Rc::new(ImageTexture::new(image));
+            Rc::new(NormalMapped::new(base, Perturbation::NormalMap(texture)))
+        }
+    };
+    objects.push(Rc::new(Sphere::new(Vec3(0.0, 1.0, 0.0), 1.0, material)));
+}
+
+// spheres with cutout masks, hard edged and stochastic
+fn generate_cutout(objects: &mut HittableList) {
+    objects.push(Rc::new(Sphere::new(
+        Vec3(0.0, -1000.0, 0.0),
+        1000.0,
+        Rc::new(Lambertian::new(Vec3(0.5, 0.5, 0.5))),
+    )));
+
+    let mask = Rc::new(Waves::new((12.0, 6.0)));
+    let inner = Rc::new(Sphere::new(
+        Vec3(0.0, 1.0, 0.0),
+        0.5,
+        Rc::new(Metal::new(Vec3(0.7, 0.6, 0.5), 0.0)),
+    ));
+    let fence = Rc::new(Sphere::new(
+        Vec3(0.0, 1.0, 0.0),
+        1.0,
+        Rc::new(Lambertian::new(Vec3(0.2, 0.5, 0.1))),
+    ));
+    let leaves = Rc::new(Sphere::new(
+        Vec3(-4.0, 1.0, 0.0),
+        1.0,
+        Rc::new(Lambertian::new(Vec3(0.1, 0.4, 0.1))),
+    ));
+    objects.push(inner);
+    objects.push(Rc::new(AlphaMasked::new(
+        fence,
+        mask.clone(),
+        AlphaMode::Threshold(0.5),
+    )));
+    objects.push(Rc::new(AlphaMasked::new(leaves, mask, AlphaMode::Stochastic)));
+}
+
+// diffuse, glossy and glass spheres lit by 2 sphere lights of different size under a dim sky, where light sampling
+// pays off
+fn generate_lights(objects: &mut HittableList) -> Vec<Light> {
+    objects.push(Rc::new(Sphere::new(
+        Vec3(0.0, -1000.0, 0.0),
+        1000.0,
+        Rc::new(Lambertian::new(Vec3(0.5, 0.5, 0.5))),
+    )));
+    objects.push(Rc::new(Sphere::new(
+        Vec3(-4.0, 1.0, 0.0),
+        1.0,
+        Rc::new(Lambertian::new(Vec3(0.4, 0.2, 0.1))),
+    )));
+    let glossy = PrincipledParams {
+        base_color: Vec3(0.7, 0.6, 0.5),
+        metallic: 1.0,
+        roughness: 0.3,
+        ..Default::default()
+    };
+    objects.push(Rc::new(Sphere::new(
         Vec3(0.0, 1.0, 0.0),
-        20.0,
-        0.1,
-        10.0,
-    );
-    let renderer = Renderer::new(400, aspect_ratio, 10, 10);
+        1.0,
+        Rc::new(Principled::new(glossy)),
+    )));
+    objects.push(Rc::new(Sphere::new(
+        Vec3(4.0, 1.0, 0.0),
+        1.0,
+        Rc::new(Dielectric::new(1.5)),
+    )));
+    vec![
+        Light::sphere(objects, Vec3(-2.0, 3.5, 2.5), 0.2, Vec3(150.0, 130.0, 100.0)),
+        Light::sphere(objects, Vec3(3.0, 4.0, -3.0), 1.0, Vec3(3.0, 4.0, 5.0)),
+        Light::Sky { scale: 0.05 },
+    ]
+}
+
+// The lights scene with participating media: a ball of smoke, which has no surface of its own, and glass filled with
+// a medium that absorbs red light. outside is the medium around them.
+fn generate_media(objects: &mut HittableList, outside: &Option<Rc<Medium>>) -> Vec<Light> {
+    objects.push(Rc::new(Sphere::new(
+        Vec3(0.0, -1000.0, 0.0),
+        1000.0,
+        Rc::new(Lambertian::new(Vec3(0.5, 0.5, 0.5))),
+    )));
+    let smoke = Medium::homogeneous(Vec3(0.1, 0.1, 0.1), Vec3(1.5, 1.5, 1.5), 0.3);
+    objects.push(Rc::new(MediumBoundary::new(
+        Rc::new(Sphere::new(Vec3(-4.0, 1.0, 0.0), 1.0, Rc::new(Interface))),
+        MediumInterface { inside: Some(Rc::new(smoke)), outside: outside.clone() },
+    )));
+    let glossy = PrincipledParams {
+        base_color: Vec3(0.7, 0.6, 0.5),
+        metallic: 1.0,
+        roughness: 0.3,
+        ..Default::default()
+    };
+    objects.push(Rc::new(Sphere::new(
+        Vec3(0.0, 1.0, 0.0),
+        1.0,
+        Rc::new(Principled::new(glossy)),
+    )));
+    let tint = Medium::homogeneous(Vec3(0.8, 0.2, 0.1), Vec3::zero(), 0.0);
+    objects.push(Rc::new(MediumBoundary::new(
+        Rc::new(Sphere::new(Vec3(4.0, 1.0, 0.0), 1.0, Rc::new(Dielectric::new(1.5)))),
+        MediumInterface { inside: Some(Rc::new(tint)), outside: outside.clone() },
+    )));
+    vec![
+        Light::sphere(objects, Vec3(-2.0, 3.5, 2.5), 0.2, Vec3(150.0, 130.0, 100.0)),
+        Light::sphere(objects, Vec3(3.0, 4.0, -3.0), 1.0, Vec3(3.0, 4.0, 5.0)),
+        Light::Sky { scale: 0.05 },
+    ]
+}
+
+// vertical field of view of the perspective camera, in degrees
+const VFOV: f32 = 20.0;
+
+// eye_offset is non-zero for one eye of a stereo pair
+fn build_camera(
+    options: &Options,
+    aspect_ratio: f32,
+    look_from: Vec3,
+    look_at: Vec3,
+    vup: Vec3,
+    eye_offset: f32,
+) -> Box<dyn Camera> {
+    match options.camera.as_str() {
+        "equirectangular" => Box::new(EquirectangularCamera::new(look_from, look_at, vup, 360.0, eye_offset)),
+        "vr180" => Box::new(EquirectangularCamera::new(look_from, look_at, vup, 180.0, eye_offset)),
+        _ => {
+            let (eye, target) = stereo_eye(look_from, look_at, vup, eye_offset, options.convergence);
+            match options.camera.as_str() {
+                "perspective" => {
+                    let aperture_shape = match (&options.aperture_image, options.aperture_blades) {
+                        (Some(path), _) => ApertureShape::image(
+                            Image::read_pnm(path).unwrap_or_else(|e| panic!("Cannot read {path}: {e}")),
+                        ),
+                        (None, Some(blades)) => ApertureShape::Polygon { blades, rotation: options.aperture_rotation },
+                        (None, None) => ApertureShape::Circle,
+                    };
+                    let (aperture, focus_distance) = (options.aperture, options.focus_distance);
+                    Box::new(
+                        PerspectiveCamera::new(aspect_ratio, eye, target, vup, VFOV, aperture, focus_distance)
+                            .with_aperture_shape(aperture_shape)
+                            .with_vignetting(options.cos4_falloff, options.optical_vignetting),
+                    )
+                }
+                "orthographic" => Box::new(OrthographicCamera::new(aspect_ratio, eye, target, vup, 4.0)),
+                "fisheye" => Box::new(FisheyeCamera::new(aspect_ratio, eye, target, vup, 180.0)),
+                "realistic" => {
+                    let (path, table) = match &options.lens {
+                        Some(path) => (
+                            path.as_str(),
+                            fs::read_to_string(path).unwrap_or_else(|e| panic!("Cannot read {path}: {e}")),
+                        ),
+                        None => ("the default lens", String::from(DOUBLE_GAUSS_50MM)),
+                    };
+                    let elements =
+                        LensElement::parse_table(&table).unwrap_or_else(|e| panic!("Cannot read {path}: {e}"));
+                    Box::new(RealisticCamera::new(
+                        aspect_ratio,
+                        eye,
+                        target,
+                        vup,
+                        elements,
+                        options.focus_distance,
+                    ))
+                }
+                "panorama" => Box::new(PanoramaCamera::new(eye, target, vup, 180.0, 60.0)),
+                camera => panic!("Unknown camera {camera}"),
+            }
+        }
+    }
+}
+
+fn build_sampler(options: &Options) -> Box<dyn Sampler> {
+    match options.sampler.as_str() {
+        "independent" => Box::new(IndependentSampler::new()),
+        "stratified" => Box::new(StratifiedSampler::new(options.samples_per_pixel)),
+        "halton" => Box::new(HaltonSampler::new()),
+        "sobol" => Box::new(SobolSampler::new()),
+        "blue-noise" => Box::new(BlueNoiseSampler::new()),
+        sampler => panic!("Unknown sampler {sampler}"),
+    }
+}
+
+fn build_integrator(options: &Options) -> Box<dyn Integrator> {
+    match options.integrator.as_str() {
+        // the others would quietly render the scene without it
+        integrator if options.fog.is_some() && integrator != "volpath" => {
+            panic!("--fog requires --integrator volpath, not {integrator}")
+        }
+        // debug views show the surfaces, which they find through the media's boundaries
+        integrator if options.scene == "media" && integrator != "volpath" && debug_mode(integrator).is_none() => {
+            panic!("--scene media requires --integrator volpath, not {integrator}")
+        }
+        "path" => Box::new(PathIntegrator::naive(options.max_depth, options.min_depth)),
+        "mis" => Box::new(PathIntegrator::mis(options.max_depth, options.min_depth)),
+        "direct" => Box::new(DirectLightingIntegrator::new(options.max_depth)),
+        "ao" => Box::new(AmbientOcclusionIntegrator::new(options.ao_distance)),
+        "whitted" => Box::new(WhittedIntegrator::new(options.max_depth)),
+        "bdpt" => Box::new(BdptIntegrator::new(options.max_depth, options.min_depth)),
+        "volpath" => Box::new(VolumePathIntegrator::new(options.max_depth, options.min_depth)),
+        "sppm" => Box::new(SppmIntegrator::new(
+            options.max_depth,
+            options.min_depth,
+            options.photons,
+            options.photon_radius,
+        )),
+        // splats are converted to RGB with the wavelengths of the camera ray, but chains splat paths of their own
+        "mlt" if options.spectral => panic!("The mlt integrator does not support --spectral"),
+        "mlt" => Box::new(MltIntegrator::new(
+            Box::new(PathIntegrator::mis(options.max_depth, options.min_depth)),
+            options.mlt_bootstrap,
+            options.mlt_chains,
+            options.mlt_sigma,
+            options.mlt_large_step,
+        )),
+        integrator => match debug_mode(integrator) {
+            Some(mode) => Box::new(DebugIntegrator::new(mode)),
+            None => panic!("Unknown integrator {integrator}"),
+        },
+    }
+}
 
+// the mode of the debug-<mode> integrators, e.g. debug-shading-normal or debug-bvh-visits
+fn debug_mode(integrator: &str) -> Option<DebugMode> {
+    let mode = integrator.strip_prefix("debug-")?;
+    let mode = mode.parse().unwrap_or_else(|e| panic!("{e}"));
+    if matches!(mode, DebugMode::AabbTests | DebugMode::BvhVisits) && !cfg!(feature = "traversal-counts") {
+        panic!("--integrator {integrator} requires building with --features traversal-counts");
+    }
+    Some(mode)
+}
+
+// denoised image as sample.tiff, and in linear float as sample.denoised.exr
+fn write_denoised(frame: &Frame) {
+    let now = Instant::now();
+    let pixels = Denoiser::default().denoise(frame);
+    eprintln!("Denoised in {:.2} seconds.", now.elapsed().as_secs_f32());
+
+    let mut tiff_file = TiffFile::new("sample.tiff", frame.width, frame.height);
+    tiff_file.write(&encode_rgb8(pixels.iter().copied()));
+    let mut channels: Vec<Channel> = ["R", "G", "B"]
+        .iter()
+        .enumerate()
+        .map(|(c, name)| Channel { name: String::from(*name), values: pixels.iter().map(|p| p[c]).collect() })
+        .collect();
+    write_exr("sample.denoised.exr", frame.width, frame.height, &mut channels)
+        .unwrap_or_else(|e| panic!("Cannot write sample.denoised.exr: {e}"));
+}
+
+fn main() {
+    let options = Options::parse();
+    if let Some(path) = &options.denoise_input {
+        let frame = Frame::read_exr(path).unwrap_or_else(|e| panic!("Cannot read {path}: {e}"));
+        write_denoised(&frame);
+        return;
+    }
+    let (look_from, look_at, vup) = (Vec3(13.0, 2.0, 3.0), Vec3(0.0, 0.0, 0.0), Vec3(0.0, 1.0, 0.0));
+    let eye_aspect_ratio = match options.camera.as_str() {
+        "panorama" => 3.0,
+        "equirectangular" => 2.0,
+        "vr180" => 1.0,
+        _ => 16.0 / 9.0,
+    };
+    let (aspect_ratio, camera): (f32, Box<dyn Camera>) = match options.stereo {
+        None => (
+            eye_aspect_ratio,
+            build_camera(&options, eye_aspect_ratio, look_from, look_at, vup, 0.0),
+        ),
+        Some(layout) => {
+            let aspect_ratio = match layout {
+                StereoLayout::SideBySide => 2.0 * eye_aspect_ratio,
+                StereoLayout::OverUnder => 0.5 * eye_aspect_ratio,
+            };
+            let offset = 0.5 * options.ipd;
+            let left = build_camera(&options, eye_aspect_ratio, look_from, look_at, vup, -offset);
+            let right = build_camera(&options, eye_aspect_ratio, look_from, look_at, vup, offset);
+            (aspect_ratio, Box::new(StereoCamera::new(left, right, layout)))
+        }
+    };
+    // debug integrators show their values as they are: in RGB, without exposure, clamping, filtering across pixels,
+    // outlier rejection, denoising or gamma
+    let debug_mode = debug_mode(&options.integrator);
+    let debug = debug_mode.is_some();
+    let denoise = options.denoise && !debug;
+    let mut renderer = Renderer::new(400, aspect_ratio, options.samples_per_pixel, build_integrator(&options));
+    renderer.spectral = options.spectral && !debug;
+    renderer.min_samples_per_pixel = options.min_samples_per_pixel.min(options.samples_per_pixel);
+    renderer.noise_threshold = options.noise_threshold;
+    renderer.time_budget = options.time_budget.map(Duration::from_secs_f32);
+    renderer.clamp_direct = options.clamp_direct.filter(|_| !debug);
+    renderer.clamp_indirect = options.clamp_indirect.filter(|_| !debug);
+    renderer.crop = match options.crop_normalized {
+        Some(coords) => Some(CropWindow::from_normalized(
+            coords,
+            renderer.img_width,
+            renderer.img_height,
+        )),
+        None => options.crop,
+    };
+    if let Some(window) = renderer.crop {
+        assert!(
+            window.x0 < window.x1
+                && window.x1 <= renderer.img_width
+                && window.y0 < window.y1
+                && window.y1 <= renderer.img_height,
+            "Crop window {window:?} is empty or outside the {}x{} image",
+            renderer.img_width,
+            renderer.img_height
+        );
+    }
+    if let Some(shutter) = options.shutter.filter(|_| !debug) {
+        let f_number = camera.f_number().unwrap_or_else(|| {
+            panic!("--shutter requires a lens aperture: --camera perspective with a non-zero --aperture, or realistic")
+        });
+        let exposure = Exposure { shutter, f_number, iso: options.iso };
+        eprintln!(
+            "f/{f_number:.1}, {shutter} s, ISO {}: EV100 = {:.2}",
+            options.iso,
+            exposure.ev100()
+        );
+        renderer.exposure = exposure.scale();
+    }
+
+    // homogeneous fog the camera is in, everywhere up to the sky or within fog_radius of the camera
+    let fog = options
+        .fog
+        .map(|density| Rc::new(Medium::fog(density, options.fog_albedo, options.fog_g)));
     let mut objects = HittableList::new();
-    generate_spheres(&mut objects);
-    let mut buffer = vec![0_u8; (renderer.img_height * renderer.img_width * 3) as usize];
+    if let (Some(fog), Some(radius)) = (&fog, options.fog_radius) {
+        objects.push(Rc::new(MediumBoundary::new(
+            Rc::new(Sphere::new(look_from, radius, Rc::new(Interface))),
+            MediumInterface { inside: Some(fog.clone()), outside: None },
+        )));
+    }
+    let mut lights = vec![Light::Sky { scale: 1.0 }];
+    match options.scene.as_str() {
+        "spheres" => generate_spheres(&mut objects),
+        "dispersion" => generate_dispersion(&mut objects),
+        "principled" => generate_principled(&mut objects),
+        "layered" => generate_layered(&mut objects),
+        "bumpy" => generate_bumpy(&mut objects, &options.normal_map),
+        "cutout" => generate_cutout(&mut objects),
+        "lights" => lights = generate_lights(&mut objects),
+        "media" => lights = generate_media(&mut objects, &fog),
+        scene => panic!("Unknown scene {scene}"),
+    }
+    let mut scene = Scene::new(objects, lights);
+    scene.medium = fog;
+    let mut aovs = options.aovs.clone();
+    if denoise {
+        aovs.extend(Frame::AOVS.iter().filter(|aov| !options.aovs.contains(aov)));
+    }
+    if let Some(aov) = debug_mode.and_then(DebugMode::id_aov).filter(|aov| !aovs.contains(aov)) {
+        aovs.push(aov);
+    }
+    let (filter, filter_radius) = match debug {
+        true => (Filter::Box, 0.5),
+        false => (options.filter, options.filter_radius),
+    };
+    let mut film = Film::new(renderer.img_width, renderer.img_height, filter, filter_radius).with_aovs(&aovs);
+
+    if let Some(path) = &options.checkpoint {
+        let interval = Duration::from_secs_f32(options.checkpoint_interval);
+        let checkpoint = Checkpoint::new(path, interval, options.checkpoint_key());
+        if checkpoint
+            .load(&mut film)
+            .unwrap_or_else(|e| panic!("Cannot read {path}: {e}"))
+        {
+            eprintln!("Resuming from {path}");
+        }
+        renderer.checkpoint = Some(checkpoint);
+    }
 
     let now = Instant::now();
-    renderer.render(&objects, &camera, &mut buffer);
+    let mut sampler = build_sampler(&options);
+    renderer.render(&scene, camera.as_ref(), sampler.as_mut(), &mut film);
     let elapsed_time = now.elapsed();
     eprintln!("\nDone.");
     eprintln!("{} seconds.", elapsed_time.as_secs());
 
-    let mut tiff_file = TiffFile::new("sample.tiff", renderer.img_width, renderer.img_height);
-    tiff_file.write(&buffer);
+    if let (Some(window), false) = (renderer.crop, options.crop_full_size) {
+        film = film.cropped(window);
+    }
+    if let Some(k) = options.outlier_rejection.filter(|_| !debug) {
+        film.reject_outliers(k);
+    }
+    match (debug_mode, denoise) {
+        // the values in float, and in colour to view them with
+        (Some(mode), _) => {
+            write_multilayer("sample.exr", &film).unwrap_or_else(|e| panic!("Cannot write sample.exr: {e}"));
+            let id_aov = mode.id_aov().and_then(|aov| film.aovs().iter().position(|&a| a == aov));
+            let pixels = (0..film.height)
+                .flat_map(|j| (0..film.width).map(move |i| (i, j)))
+                .map(|(i, j)| match id_aov {
+                    Some(k) => film.aov_pixel(k, i, j),
+                    None => film.pixel(i, j),
+                })
+                .map(|value| mode.display(value, options.debug_scale));
+            let mut tiff_file = TiffFile::new("sample.tiff", film.width, film.height);
+            tiff_file.write(&encode_linear_rgb8(pixels));
+        }
+        (None, true) => write_denoised(&Frame::from_film(&film)),
+        (None, false) => {
+            let mut tiff_file = TiffFile::new("sample.tiff", film.width, film.height);
+            tiff_file.write(&film.to_rgb8());
+        }
+    }
+    if let Some(path) = &options.sample_count_map {
+        let mut tiff_file = TiffFile::new(path, film.width, film.height);
+        tiff_file.write(&film.sample_counts_to_rgb8());
+    }
+    if !options.aovs.is_empty() {
+        let result = match options.aov_separate {
+            true => write_separate("sample", &film),
+            false => write_multilayer("sample.exr", &film),
+        };
+        result.unwrap_or_else(|e| panic!("Cannot write AOVs: {e}"));
+    }
 }
@@ -1,9 +1,39 @@
-use crate::hittable::HitRecord;
-use crate::pcg32::PCG32;
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+use crate::hittable::{HitRecord, Ray};
+use crate::sampler::Sampler;
+use crate::texture::Texture;
 use crate::vec3::Vec3;
 
 pub trait Material {
-    fn scatter(&self, incident: &Vec3, rec: &HitRecord, rng: &mut PCG32) -> Option<(Vec3, Vec3)>;
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<(Vec3, Vec3)>;
+    // scatter direction depends on wavelength
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+    // reflectance colour for the albedo AOV
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::one()
+    }
+    // normal the material shades with, for the shading normal AOV
+    fn shading_normal(&self, rec: &HitRecord) -> Vec3 {
+        rec.normal
+    }
+    // RGB radiance emitted towards the ray origin
+    fn emitted(&self, _ray: &Ray, _rec: &HitRecord) -> Vec3 {
+        Vec3::zero()
+    }
+    // BSDF times the cosine to the shading normal, and the solid angle pdf of scatter choosing wi (unit length), for
+    // integrators that sample lights. None whatever wi for materials that can not be evaluated, e.g. perfectly
+    // specular ones, which integrators only follow with scatter.
+    fn eval(&self, _ray: &Ray, _rec: &HitRecord, _wi: Vec3) -> Option<(Vec3, f32)> {
+        None
+    }
+    // not a surface but only the boundary of a participating medium, see Interface
+    fn is_interface(&self) -> bool {
+        false
+    }
 }
 
 pub struct Lambertian {
@@ -15,14 +45,57 @@ impl Lambertian {
     }
 }
 impl Material for Lambertian {
-    fn scatter(&self, _incident: &Vec3, rec: &HitRecord, rng: &mut PCG32) -> Option<(Vec3, Vec3)> {
-        let mut diffuse = rec.normal + Vec3::random_unit_sphere(rng).normalize();
+    fn scatter(&self, _ray: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
+        let mut diffuse = rec.normal + Vec3::random_unit_sphere(sampler).normalize();
         // catch degenerate scatter direction
         if diffuse.length2() < 1e-16 {
             diffuse = rec.normal;
         }
         Some((diffuse, self.albedo))
     }
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        self.albedo
+    }
+    // scatter is cosine distributed
+    fn eval(&self, _ray: &Ray, rec: &HitRecord, wi: Vec3) -> Option<(Vec3, f32)> {
+        let cos_theta = wi.dot(rec.normal).max(0.0);
+        Some((self.albedo * (cos_theta / PI), cos_theta / PI))
+    }
+}
+
+// Emits light from its front face and reflects none
+pub struct DiffuseLight {
+    emission: Vec3,
+}
+impl DiffuseLight {
+    pub fn new(emission: Vec3) -> DiffuseLight {
+        DiffuseLight { emission }
+    }
+}
+impl Material for DiffuseLight {
+    fn scatter(&self, _ray: &Ray, _rec: &HitRecord, _sampler: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
+        None
+    }
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::zero()
+    }
+    fn emitted(&self, _ray: &Ray, rec: &HitRecord) -> Vec3 {
+        match rec.front_face {
+            true => self.emission,
+            false => Vec3::zero(),
+        }
+    }
+}
+
+// Boundary of a participating medium without a surface of its own, which light passes straight through
+pub struct Interface;
+impl Material for Interface {
+    fn scatter(&self, ray: &Ray, _rec: &HitRecord, _sampler: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
+        Some((ray.direction, Vec3::one()))
+    }
+    fn is_interface(&self) -> bool {
+        true
+    }
 }
 
 pub struct Metal {
@@ -34,53 +107,217 @@ impl Metal {
         Metal { albedo, fuzz }
     }
 }
-fn reflect(incident: Vec3, n: Vec3) -> Vec3 {
+pub fn reflect(incident: Vec3, n: Vec3) -> Vec3 {
     incident - 2.0 * incident.dot(n) * n
 }
 impl Material for Metal {
-    fn scatter(&self, incident: &Vec3, rec: &HitRecord, rng: &mut PCG32) -> Option<(Vec3, Vec3)> {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
         // only reflect when incident is opposite normal
-        if incident.dot(rec.normal) >= 0.0 {
+        if ray.direction.dot(rec.normal) >= 0.0 {
             return None;
         }
-        let reflected = reflect(*incident, rec.normal);
-        Some((reflected + Vec3::random_unit_sphere(rng) * self.fuzz, self.albedo))
+        let reflected = reflect(ray.direction, rec.normal);
+        Some((reflected + Vec3::random_unit_sphere(sampler) * self.fuzz, self.albedo))
+    }
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        self.albedo
+    }
+}
+
+// index of refraction, optionally as a function of wavelength (in micrometres for the fitted formulas)
+pub enum Ior {
+    Constant(f32),
+    Cauchy(f32, f32),
+    Sellmeier([f32; 3], [f32; 3]),
+}
+impl Ior {
+    // RGB rendering uses the Fraunhofer d-line
+    const LAMBDA_D: f32 = 587.6;
+
+    fn at(&self, lambda: Option<f32>) -> f32 {
+        let lambda = lambda.unwrap_or(Ior::LAMBDA_D) / 1000.0;
+        let lambda2 = lambda * lambda;
+        match self {
+            Ior::Constant(eta) => *eta,
+            Ior::Cauchy(a, b) => a + b / lambda2,
+            Ior::Sellmeier(b, c) => (1.0 + (0..3).map(|i| b[i] * lambda2 / (lambda2 - c[i])).sum::<f32>()).sqrt(),
+        }
     }
 }
 
 pub struct Dielectric {
-    eta: f32,
+    ior: Ior,
 }
 impl Dielectric {
     pub fn new(eta: f32) -> Dielectric {
-        Dielectric { eta }
+        Dielectric { ior: Ior::Constant(eta) }
+    }
+    pub fn with_ior(ior: Ior) -> Dielectric {
+        Dielectric { ior }
     }
 }
-fn refract(incident: Vec3, n: Vec3, eta: f32) -> Vec3 {
+pub fn refract(incident: Vec3, n: Vec3, eta: f32) -> Vec3 {
     let cos_theta = f32::min(-incident.dot(n), 1.0);
     let r_out_perp = eta * (incident + cos_theta * n);
     let r_out_para = -(1.0 - r_out_perp.length2()).abs().sqrt() * n;
     r_out_perp + r_out_para
 }
-fn schlick_reflectance(cos_theta: f32, eta: f32) -> f32 {
+pub fn schlick_reflectance(cos_theta: f32, eta: f32) -> f32 {
     let r0 = (1.0 - eta) / (1.0 + eta);
     let r02 = r0 * r0;
     r02 + (1.0 - r02) * (1.0 - cos_theta).powf(5.0)
 }
 impl Material for Dielectric {
-    fn scatter(&self, incident: &Vec3, rec: &HitRecord, rng: &mut PCG32) -> Option<(Vec3, Vec3)> {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
+        let ior = self.ior.at(ray.wavelengths.map(|wavelengths| wavelengths.hero()));
         let eta = match rec.front_face {
-            true => 1.0 / self.eta,
-            false => self.eta,
+            true => 1.0 / ior,
+            false => ior,
         };
-        let incident_norm = incident.normalize();
+        let incident_norm = ray.direction.normalize();
         let cos_theta = (-incident_norm.dot(rec.normal)).min(1.0);
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
 
-        let refracted = match (eta * sin_theta <= 1.0) && (schlick_reflectance(cos_theta, eta) < rng.f32()) {
+        let refracted = match (eta * sin_theta <= 1.0) && (schlick_reflectance(cos_theta, eta) < sampler.get_1d()) {
             true => refract(incident_norm, rec.normal, eta),
             false => reflect(incident_norm, rec.normal), // total internal reflection
         };
-        Some((refracted, Vec3::zero()))
+        Some((refracted, Vec3::one()))
+    }
+    fn is_dispersive(&self) -> bool {
+        !matches!(self.ior, Ior::Constant(_))
+    }
+}
+
+// Thin dielectric layer over another material e.g. car paint, lacquer or varnish. Light is traced stochastically
+// through the layer: Fresnel reflection at the top interface, absorption through the layer on the way down and up,
+// and repeated internal bounces between the base and the top interface. Light the base transmits, e.g. glass, leaves
+// through the bottom of the layer.
+pub struct Coated {
+    base: Rc<dyn Material>,
+    eta: f32,
+    thickness: f32,
+    absorption: Vec3,
+}
+impl Coated {
+    const MAX_BOUNCES: u32 = 8;
+
+    pub fn new(base: Rc<dyn Material>, eta: f32, thickness: f32, absorption: Vec3) -> Coated {
+        Coated { base, eta, thickness, absorption }
+    }
+    fn transmittance(&self, cos_theta: f32) -> Vec3 {
+        let distance = self.thickness / cos_theta.abs().max(1e-4);
+        Vec3(
+            (-self.absorption.0 * distance).exp(),
+            (-self.absorption.1 * distance).exp(),
+            (-self.absorption.2 * distance).exp(),
+        )
+    }
+}
+impl Material for Coated {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
+        let incident = ray.direction.normalize();
+        let cos_theta = (-incident.dot(rec.normal)).min(1.0);
+        if schlick_reflectance(cos_theta, 1.0 / self.eta) > sampler.get_1d() {
+            return Some((reflect(incident, rec.normal), Vec3::one()));
+        }
+
+        let mut direction = refract(incident, rec.normal, 1.0 / self.eta);
+        let mut attenuation = self.transmittance(direction.dot(rec.normal));
+        for _ in 0..Coated::MAX_BOUNCES {
+            let (scattered, color) = self.base.scatter(&ray.spawn(rec, direction), rec, sampler)?;
+            let scattered = scattered.normalize();
+            let cos_inside = scattered.dot(rec.normal);
+            if cos_inside <= 0.0 {
+                // The base saw light arrive from within the layer as if from outside, so its direction is bent once
+                // more by the layer to leave at the angle the base alone would give. It has crossed the layer once.
+                let sin_inside = (1.0 - cos_inside * cos_inside).sqrt();
+                if self.eta * sin_inside > 1.0 {
+                    return None;
+                }
+                return Some((refract(scattered, rec.normal, self.eta), attenuation * color));
+            }
+            attenuation = attenuation * color * self.transmittance(cos_inside);
+
+            let sin_inside = (1.0 - cos_inside * cos_inside).sqrt();
+            let exits = self.eta * sin_inside <= 1.0 && schlick_reflectance(cos_inside, self.eta) < sampler.get_1d();
+            if exits {
+                return Some((refract(scattered, -rec.normal, self.eta), attenuation));
+            }
+            // reflected back down by the top interface
+            direction = reflect(scattered, -rec.normal);
+            attenuation = attenuation * self.transmittance(cos_inside);
+        }
+        None
+    }
+    fn is_dispersive(&self) -> bool {
+        self.base.is_dispersive()
+    }
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.base.albedo(rec)
+    }
+    fn shading_normal(&self, rec: &HitRecord) -> Vec3 {
+        self.base.shading_normal(rec)
+    }
+}
+
+pub enum Perturbation {
+    NormalMap(Rc<dyn Texture>), // tangent space normals encoded as colour
+    Bump(Rc<dyn Texture>, f32), // height field and its scale in world units
+}
+
+// Perturbs the shading normal of another material. The geometric normal is left untouched.
+pub struct NormalMapped {
+    base: Rc<dyn Material>,
+    perturbation: Perturbation,
+}
+impl NormalMapped {
+    pub fn new(base: Rc<dyn Material>, perturbation: Perturbation) -> NormalMapped {
+        NormalMapped { base, perturbation }
+    }
+}
+impl Material for NormalMapped {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
+        let mut rec = rec.clone();
+        rec.normal = self.shading_normal(&rec);
+        self.base.scatter(ray, &rec, sampler)
+    }
+    fn is_dispersive(&self) -> bool {
+        self.base.is_dispersive()
+    }
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.base.albedo(rec)
+    }
+    fn eval(&self, ray: &Ray, rec: &HitRecord, wi: Vec3) -> Option<(Vec3, f32)> {
+        let mut rec = rec.clone();
+        rec.normal = self.shading_normal(&rec);
+        self.base.eval(ray, &rec, wi)
+    }
+    fn shading_normal(&self, rec: &HitRecord) -> Vec3 {
+        let normal = match &self.perturbation {
+            Perturbation::NormalMap(texture) => {
+                let tangent = rec.tangent();
+                let bitangent = rec.normal.cross(tangent);
+                let n = 2.0 * texture.value(rec.uv, rec.p) - 1.0;
+                tangent * n.0 + bitangent * n.1 + rec.normal * n.2
+            }
+            Perturbation::Bump(texture, scale) => {
+                let delta = 1e-3;
+                let (u, v) = rec.uv;
+                let height = |u, v| texture.value((u, v), rec.p).0 * scale;
+                let h = height(u, v);
+                let dhdu = (height(u + delta, v) - h) / delta;
+                let dhdv = (height(u, v + delta) - h) / delta;
+                (rec.dpdu + dhdu * rec.normal).cross(rec.dpdv + dhdv * rec.normal)
+            }
+        };
+        if normal.length2() < 1e-12 {
+            return rec.normal;
+        }
+        let normal = normal.normalize();
+        match normal.dot(rec.normal) < 0.0 {
+            true => -normal,
+            false => normal,
+        }
     }
 }
@@ -0,0 +1,120 @@
+// Participating media such as fog and smoke, which absorb and scatter light along rays instead of only at surfaces
+
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+use crate::hittable::{HitRecord, Ray};
+use crate::sampler::Sampler;
+use crate::vec3::Vec3;
+
+// Homogeneous medium with RGB absorption and scattering coefficients per unit distance, and a Henyey-Greenstein phase
+// function with asymmetry g: 0 scatters in all directions alike, positive g mostly forwards, negative backwards
+pub struct Medium {
+    sigma_a: Vec3,
+    sigma_s: Vec3,
+    g: f32,
+}
+
+// what happened to a ray travelling through a medium
+pub struct MediumSample {
+    pub scattered: Option<f32>, // ray parameter of the point it scattered at, None when it went all the way
+    pub weight: Vec3,           // transmittance and scattering over the probability of the sample
+}
+
+impl Medium {
+    pub fn homogeneous(sigma_a: Vec3, sigma_s: Vec3, g: f32) -> Medium {
+        Medium { sigma_a, sigma_s, g }
+    }
+    // fog of the given density, i.e. extinction coefficient, of which albedo is scattered and the rest absorbed
+    pub fn fog(density: f32, albedo: f32, g: f32) -> Medium {
+        Medium::homogeneous(
+            Vec3::one() * (density * (1.0 - albedo)),
+            Vec3::one() * (density * albedo),
+            g,
+        )
+    }
+
+    // fraction of light left after travelling along ray from t = 0 to t, in the colour space of ray
+    pub fn transmittance(&self, ray: &Ray, t: f32) -> Vec3 {
+        let distance = t * ray.direction.length();
+        let sigma_t = ray.spectrum(self.sigma_a + self.sigma_s);
+        // a channel without extinction passes everything, also over an endless distance
+        let channel = |sigma_t: f32| match sigma_t > 0.0 {
+            true => (-sigma_t * distance).exp(),
+            false => 1.0,
+        };
+        Vec3(channel(sigma_t.0), channel(sigma_t.1), channel(sigma_t.2))
+    }
+
+    // Samples where along ray, up to t_max, light scatters, with exponential distances for the extinction of one of
+    // the channels chosen at random. The probability combines all channels, so that none of them is left noisy.
+    pub fn sample(&self, ray: &Ray, t_max: f32, sampler: &mut dyn Sampler) -> MediumSample {
+        let sigma_t = ray.spectrum(self.sigma_a + self.sigma_s);
+        let sigma_s = ray.spectrum(self.sigma_s);
+        let (u1, u2) = sampler.get_2d();
+        let channel = ((u1 * 3.0) as usize).min(2);
+        let length = ray.direction.length();
+        let distance = -(1.0 - u2).ln() / sigma_t[channel];
+        let scatter = distance < t_max * length;
+        let t = match scatter {
+            true => distance / length,
+            false => t_max,
+        };
+
+        let transmittance = self.transmittance(ray, t);
+        let density = match scatter {
+            true => sigma_t * transmittance,
+            false => transmittance,
+        };
+        let pdf = (density.0 + density.1 + density.2) / 3.0;
+        let weight = match (pdf > 0.0, scatter) {
+            (false, _) => Vec3::zero(),
+            (true, true) => sigma_s * transmittance / pdf,
+            (true, false) => transmittance / pdf,
+        };
+        MediumSample { scattered: Some(t).filter(|_| scatter), weight }
+    }
+
+    // Henyey-Greenstein phase function for light arriving along wo and scattered to wi, both unit length. It is
+    // normalised over the sphere, so it is also the pdf of sample_phase.
+    pub fn phase(&self, wo: Vec3, wi: Vec3) -> f32 {
+        let cos_theta = wo.dot(wi);
+        let denominator = 1.0 + self.g * self.g - 2.0 * self.g * cos_theta;
+        (1.0 - self.g * self.g) / (4.0 * PI * denominator * denominator.max(1e-12).sqrt())
+    }
+    // scattered direction for light arriving along wo, distributed as the phase function
+    pub fn sample_phase(&self, wo: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let (u1, u2) = sampler.get_2d();
+        let g = self.g;
+        let cos_theta = match g.abs() < 1e-3 {
+            true => 1.0 - 2.0 * u1,
+            false => {
+                let square = (1.0 - g * g) / (1.0 + g - 2.0 * g * u1);
+                (1.0 + g * g - square * square) / (2.0 * g)
+            }
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+        let (tangent, bitangent) = wo.onb();
+        tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + wo * cos_theta
+    }
+}
+
+// The media on the two sides of the surface of an object: inside, behind its front faces, and outside. None is vacuum.
+#[derive(Clone)]
+pub struct MediumInterface {
+    pub inside: Option<Rc<Medium>>,
+    pub outside: Option<Rc<Medium>>,
+}
+
+// the medium a ray leaving rec in direction travels through, when it was in medium before
+pub fn next_medium(rec: &HitRecord, direction: Vec3, medium: &Option<Rc<Medium>>) -> Option<Rc<Medium>> {
+    match &rec.medium_interface {
+        // the normal faces the side the ray came from, so only rays going through the surface change medium
+        Some(interface) if direction.dot(rec.geometric_normal) < 0.0 => match rec.front_face {
+            true => interface.inside.clone(),
+            false => interface.outside.clone(),
+        },
+        _ => medium.clone(),
+    }
+}
@@ -0,0 +1,169 @@
+// Primary sample space Metropolis light transport (Kelemen et al. 2002). A path traced by an integrator is a function
+// of the random numbers it consumed, its primary sample. Markov chains of primary samples, mutated and accepted with
+// the Metropolis-Hastings rule, visit the paths in proportion to their luminance, so once a chain has found a bright
+// but hard to sample path it explores the paths nearby instead of starting over. Large steps, which replace the whole
+// primary sample, keep chains from getting stuck. The image only comes out right up to a factor, the average
+// luminance of the paths, which a bootstrap pass of independent samples estimates before the chains start.
+
+use std::cell::RefCell;
+
+use crate::camera::Camera;
+use crate::hittable::Ray;
+use crate::integrator::{Integrator, Radiance, Splat};
+use crate::sampler::{MltSampler, Sampler};
+use crate::scene::Scene;
+use crate::vec3::Vec3;
+
+const CAMERA_STREAM: usize = 0;
+const PATH_STREAM: usize = 1;
+
+// a path through film coordinates s and t, see Camera::get_ray
+#[derive(Clone, Copy)]
+struct PathSample {
+    s: f32,
+    t: f32,
+    direct: Vec3,
+    indirect: Vec3,
+    luminance: f32,
+}
+
+struct Chain {
+    sampler: MltSampler,
+    current: PathSample,
+}
+
+struct Chains {
+    chains: Vec<Chain>,
+    normalization: f32, // average luminance of all paths
+    next: usize,
+}
+
+// Renders with chains that each take one mutation per camera sample of the renderer, in turn. What they find reaches
+// the film through splats, the radiance along the camera ray itself is not used.
+pub struct MltIntegrator {
+    path: Box<dyn Integrator>, // traces the path of a primary sample, without splats of its own
+    bootstrap_samples: u32,
+    chain_count: u32,
+    sigma: f32,
+    large_step_probability: f32,
+    chains: RefCell<Option<Chains>>, // started on first use, when the scene and camera are known
+}
+
+impl MltIntegrator {
+    pub fn new(
+        path: Box<dyn Integrator>,
+        bootstrap_samples: u32,
+        chain_count: u32,
+        sigma: f32,
+        large_step_probability: f32,
+    ) -> MltIntegrator {
+        MltIntegrator {
+            path,
+            bootstrap_samples,
+            chain_count,
+            sigma,
+            large_step_probability,
+            chains: RefCell::new(None),
+        }
+    }
+
+    fn evaluate(&self, scene: &Scene, camera: &dyn Camera, sampler: &mut MltSampler) -> PathSample {
+        sampler.start_stream(CAMERA_STREAM);
+        let (s, t) = sampler.get_2d();
+        let mut sample = PathSample { s, t, direct: Vec3::zero(), indirect: Vec3::zero(), luminance: 0.0 };
+        let Some((ray, weight)) = camera.get_ray(s, t, sampler) else {
+            return sample;
+        };
+        sampler.start_stream(PATH_STREAM);
+        let radiance = self.path.li(ray, scene, camera, sampler);
+        (sample.direct, sample.indirect) = (radiance.direct * weight, radiance.indirect * weight);
+        sample.luminance = (sample.direct + sample.indirect).luminance().max(0.0);
+        sample
+    }
+
+    // Evaluates independent primary samples for the normalization, and starts the chains at some of them picked in
+    // proportion to their luminance, which spares the chains a burn-in period
+    fn bootstrap(&self, scene: &Scene, camera: &dyn Camera) -> Chains {
+        let new_sampler = |stream: u64| MltSampler::new(stream, self.sigma, self.large_step_probability);
+        let luminances: Vec<f32> = (0..self.bootstrap_samples)
+            .map(|i| self.evaluate(scene, camera, &mut new_sampler(i as u64)).luminance)
+            .collect();
+        let total: f32 = luminances.iter().sum();
+        let mut chains = Chains {
+            chains: Vec::new(),
+            normalization: total / self.bootstrap_samples as f32,
+            next: 0,
+        };
+        if total <= 0.0 {
+            return chains;
+        }
+
+        // stratified over the cumulative luminance
+        let mut starts = Vec::new();
+        let mut cumulative = 0.0;
+        for (i, &luminance) in luminances.iter().enumerate() {
+            cumulative += luminance;
+            while starts.len() < self.chain_count as usize
+                && (starts.len() as f32 + 0.5) / self.chain_count as f32 * total < cumulative
+            {
+                starts.push(i as u64);
+            }
+        }
+        for (c, &index) in starts.iter().enumerate() {
+            // replaying the bootstrap sample restores its primary sample, after which the chain goes its own way
+            let mut sampler = new_sampler(index);
+            let current = self.evaluate(scene, camera, &mut sampler);
+            sampler.continue_on(self.bootstrap_samples as u64 + c as u64);
+            chains.chains.push(Chain { sampler, current });
+        }
+        chains
+    }
+
+    // a path sample splatted with weight, as a share of the expected value of the chain's next state
+    fn splats(&self, sample: &PathSample, weight: f32, normalization: f32) -> [Splat; 2] {
+        let scale = match sample.luminance > 0.0 {
+            true => weight * normalization / sample.luminance,
+            false => 0.0,
+        };
+        let (s, t) = (sample.s, sample.t);
+        [
+            Splat { s, t, radiance: sample.direct * scale, bounces: 1 },
+            Splat { s, t, radiance: sample.indirect * scale, bounces: 2 },
+        ]
+    }
+}
+
+impl Integrator for MltIntegrator {
+    fn li(&self, _ray: Ray, scene: &Scene, camera: &dyn Camera, sampler: &mut dyn Sampler) -> Radiance {
+        let mut radiance = Radiance::zero();
+        let mut chains = self.chains.borrow_mut();
+        let chains = chains.get_or_insert_with(|| self.bootstrap(scene, camera));
+        if chains.chains.is_empty() {
+            return radiance;
+        }
+        let normalization = chains.normalization;
+        let index = chains.next;
+        chains.next = (index + 1) % chains.chains.len();
+        let chain = &mut chains.chains[index];
+
+        chain.sampler.start_iteration();
+        let proposed = self.evaluate(scene, camera, &mut chain.sampler);
+        let accept = match chain.current.luminance > 0.0 {
+            true => (proposed.luminance / chain.current.luminance).min(1.0),
+            false => 1.0,
+        };
+        // both states are splatted with the probability of the chain moving on to them, which wastes no proposal
+        radiance.splats.extend(self.splats(&proposed, accept, normalization));
+        radiance
+            .splats
+            .extend(self.splats(&chain.current, 1.0 - accept, normalization));
+        match sampler.get_1d() < accept {
+            true => {
+                chain.current = proposed;
+                chain.sampler.accept();
+            }
+            false => chain.sampler.reject(),
+        }
+        radiance
+    }
+}
@@ -0,0 +1,271 @@
+// Command line options, e.g. `cargo run --release -- --scene dispersion --spectral`
+
+use std::env;
+
+use crate::aov::Aov;
+use crate::camera::StereoLayout;
+use crate::film::{CropWindow, Filter};
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub scene: String,
+    pub camera: String,
+    pub lens: Option<String>,
+    pub aperture: f32,
+    pub focus_distance: f32,
+    pub aperture_blades: Option<u32>,
+    pub aperture_rotation: f32,
+    pub aperture_image: Option<String>,
+    pub shutter: Option<f32>,
+    pub iso: f32,
+    pub cos4_falloff: bool,
+    pub optical_vignetting: f32,
+    pub stereo: Option<StereoLayout>,
+    pub ipd: f32,
+    pub convergence: f32,
+    pub spectral: bool,
+    pub filter: Filter,
+    pub filter_radius: f32,
+    pub normal_map: Option<String>,
+    pub sampler: String,
+    pub samples_per_pixel: u32,
+    pub min_samples_per_pixel: u32,
+    pub noise_threshold: Option<f32>,
+    pub sample_count_map: Option<String>,
+    pub time_budget: Option<f32>,
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval: f32,
+    pub crop: Option<CropWindow>,
+    pub crop_normalized: Option<[f32; 4]>,
+    pub crop_full_size: bool,
+    pub aovs: Vec<Aov>,
+    pub aov_separate: bool,
+    pub denoise: bool,
+    pub denoise_input: Option<String>,
+    pub clamp_direct: Option<f32>,
+    pub clamp_indirect: Option<f32>,
+    pub outlier_rejection: Option<f32>,
+    pub max_depth: u32,
+    pub min_depth: u32,
+    pub integrator: String,
+    pub ao_distance: f32,
+    pub photons: u32,
+    pub photon_radius: f32,
+    pub mlt_bootstrap: u32,
+    pub mlt_chains: u32,
+    pub mlt_sigma: f32,
+    pub mlt_large_step: f32,
+    pub fog: Option<f32>,
+    pub fog_albedo: f32,
+    pub fog_g: f32,
+    pub fog_radius: Option<f32>,
+    pub debug_scale: Option<f32>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            scene: String::from("spheres"),
+            camera: String::from("perspective"),
+            lens: None,
+            aperture: 0.1,
+            focus_distance: 10.0,
+            aperture_blades: None,
+            aperture_rotation: 0.0,
+            aperture_image: None,
+            shutter: None,
+            iso: 100.0,
+            cos4_falloff: false,
+            optical_vignetting: 0.0,
+            stereo: None,
+            ipd: 0.064,
+            convergence: f32::INFINITY,
+            spectral: false,
+            filter: Filter::Box,
+            filter_radius: 0.5,
+            normal_map: None,
+            sampler: String::from("independent"),
+            samples_per_pixel: 10,
+            min_samples_per_pixel: 16,
+            noise_threshold: None,
+            sample_count_map: None,
+            time_budget: None,
+            checkpoint: None,
+            checkpoint_interval: 300.0,
+            crop: None,
+            crop_normalized: None,
+            crop_full_size: false,
+            aovs: Vec::new(),
+            aov_separate: false,
+            denoise: false,
+            denoise_input: None,
+            clamp_direct: None,
+            clamp_indirect: None,
+            outlier_rejection: None,
+            max_depth: 256,
+            min_depth: 5,
+            integrator: String::from("path"),
+            ao_distance: 1.0,
+            photons: 100_000,
+            photon_radius: 0.1,
+            mlt_bootstrap: 100_000,
+            mlt_chains: 1000,
+            mlt_sigma: 0.01,
+            mlt_large_step: 0.3,
+            fog: None,
+            fog_albedo: 0.9,
+            fog_g: 0.0,
+            fog_radius: None,
+            debug_scale: None,
+        }
+    }
+}
+
+impl Options {
+    // Every option that changes the samples of a render, so that a checkpoint is only resumed by the same render. How
+    // many samples to take, and for how long, may change, as well as where to save and how the film is processed and
+    // written out at the end.
+    pub fn checkpoint_key(&self) -> String {
+        let key = Options {
+            samples_per_pixel: 0,
+            min_samples_per_pixel: 0,
+            noise_threshold: None,
+            sample_count_map: None,
+            time_budget: None,
+            checkpoint: None,
+            checkpoint_interval: 0.0,
+            outlier_rejection: None,
+            crop_full_size: false,
+            aov_separate: false,
+            debug_scale: None,
+            denoise_input: None,
+            ..self.clone()
+        };
+        format!("{key:?}")
+    }
+
+    pub fn parse() -> Options {
+        let mut options = Options::default();
+        let mut args = env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--scene" => options.scene = args.next().expect("--scene requires a value"),
+                "--camera" => options.camera = args.next().expect("--camera requires a value"),
+                "--lens" => options.lens = Some(parse_value(&arg, args.next())),
+                "--aperture" => options.aperture = parse_value(&arg, args.next()),
+                "--focus-distance" => options.focus_distance = parse_value(&arg, args.next()),
+                "--aperture-blades" => {
+                    let blades = parse_value(&arg, args.next());
+                    if blades < 3 {
+                        panic!("--aperture-blades requires at least 3 blades");
+                    }
+                    options.aperture_blades = Some(blades);
+                }
+                "--aperture-rotation" => options.aperture_rotation = parse_value(&arg, args.next()),
+                "--aperture-image" => options.aperture_image = Some(parse_value(&arg, args.next())),
+                "--shutter" => options.shutter = Some(parse_value(&arg, args.next())),
+                "--iso" => options.iso = parse_value(&arg, args.next()),
+                "--cos4-falloff" => options.cos4_falloff = true,
+                "--optical-vignetting" => options.optical_vignetting = parse_value(&arg, args.next()),
+                "--stereo" => {
+                    options.stereo = match args.next().as_deref() {
+                        Some("side-by-side") => Some(StereoLayout::SideBySide),
+                        Some("over-under") => Some(StereoLayout::OverUnder),
+                        _ => panic!("--stereo requires side-by-side or over-under"),
+                    }
+                }
+                "--ipd" => options.ipd = parse_value(&arg, args.next()),
+                "--convergence" => options.convergence = parse_value(&arg, args.next()),
+                "--spectral" => options.spectral = true,
+                "--filter" => {
+                    options.filter = match args.next().as_deref() {
+                        Some("box") => Filter::Box,
+                        Some("tent") => Filter::Tent,
+                        Some("gaussian") => Filter::Gaussian,
+                        Some("mitchell") => Filter::Mitchell,
+                        Some("lanczos") => Filter::Lanczos,
+                        _ => panic!("--filter requires box, tent, gaussian, mitchell or lanczos"),
+                    }
+                }
+                "--filter-radius" => options.filter_radius = parse_value(&arg, args.next()),
+                "--normal-map" => options.normal_map = Some(args.next().expect("--normal-map requires a path")),
+                "--sampler" => options.sampler = args.next().expect("--sampler requires a value"),
+                "--spp" | "--max-spp" => options.samples_per_pixel = parse_value(&arg, args.next()),
+                "--min-spp" => options.min_samples_per_pixel = parse_value(&arg, args.next()),
+                "--noise-threshold" => options.noise_threshold = Some(parse_value(&arg, args.next())),
+                "--sample-count-map" => options.sample_count_map = Some(parse_value(&arg, args.next())),
+                "--time-budget" => options.time_budget = Some(parse_value(&arg, args.next())),
+                "--checkpoint" => options.checkpoint = Some(parse_value(&arg, args.next())),
+                "--checkpoint-interval" => options.checkpoint_interval = parse_value(&arg, args.next()),
+                "--crop" => {
+                    let [x0, y0, x1, y1] = parse_list(&arg, args.next());
+                    options.crop = Some(CropWindow { x0, y0, x1, y1 });
+                }
+                "--crop-normalized" => options.crop_normalized = Some(parse_list(&arg, args.next())),
+                "--crop-output" => {
+                    options.crop_full_size = match args.next().as_deref() {
+                        Some("cropped") => false,
+                        Some("full") => true,
+                        _ => panic!("--crop-output requires cropped or full"),
+                    }
+                }
+                "--aovs" => {
+                    let value: String = parse_value(&arg, args.next());
+                    options.aovs = value
+                        .split(',')
+                        .map(|name| name.parse().unwrap_or_else(|e| panic!("{e}")))
+                        .collect();
+                }
+                "--aov-layout" => {
+                    options.aov_separate = match args.next().as_deref() {
+                        Some("multilayer") => false,
+                        Some("separate") => true,
+                        _ => panic!("--aov-layout requires multilayer or separate"),
+                    }
+                }
+                "--denoise" => options.denoise = true,
+                "--denoise-input" => options.denoise_input = Some(parse_value(&arg, args.next())),
+                "--clamp-direct" => options.clamp_direct = Some(parse_value(&arg, args.next())),
+                "--clamp-indirect" => options.clamp_indirect = Some(parse_value(&arg, args.next())),
+                "--outlier-rejection" => options.outlier_rejection = Some(parse_value(&arg, args.next())),
+                "--max-depth" => options.max_depth = parse_value(&arg, args.next()),
+                "--min-depth" => options.min_depth = parse_value(&arg, args.next()),
+                "--integrator" => options.integrator = args.next().expect("--integrator requires a value"),
+                "--ao-distance" => options.ao_distance = parse_value(&arg, args.next()),
+                "--photons" => options.photons = parse_value(&arg, args.next()),
+                "--photon-radius" => options.photon_radius = parse_value(&arg, args.next()),
+                "--mlt-bootstrap" => options.mlt_bootstrap = parse_value(&arg, args.next()),
+                "--mlt-chains" => options.mlt_chains = parse_value(&arg, args.next()),
+                "--mlt-sigma" => options.mlt_sigma = parse_value(&arg, args.next()),
+                "--mlt-large-step" => options.mlt_large_step = parse_value(&arg, args.next()),
+                "--fog" => options.fog = Some(parse_value(&arg, args.next())),
+                "--fog-albedo" => options.fog_albedo = parse_value(&arg, args.next()),
+                "--fog-g" => options.fog_g = parse_value(&arg, args.next()),
+                "--fog-radius" => options.fog_radius = Some(parse_value(&arg, args.next())),
+                "--debug-scale" => options.debug_scale = Some(parse_value(&arg, args.next())),
+                _ => panic!("Unknown argument {arg}"),
+            }
+        }
+        options
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(name: &str, value: Option<String>) -> T {
+    let value = value.unwrap_or_else(|| panic!("{name} requires a value"));
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("Invalid value for {name}: {value}"))
+}
+
+// comma separated list of N values, e.g. `--crop 100,50,200,150`
+fn parse_list<T: std::str::FromStr, const N: usize>(name: &str, value: Option<String>) -> [T; N] {
+    let value: String = parse_value(name, value);
+    let values: Vec<T> = value
+        .split(',')
+        .map(|v| parse_value(name, Some(String::from(v.trim()))))
+        .collect();
+    values
+        .try_into()
+        .unwrap_or_else(|_| panic!("{name} requires {N} comma separated values"))
+}
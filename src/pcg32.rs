@@ -5,6 +5,8 @@ pub struct PCG32 {
 
 #[rustfmt::skip]
 impl PCG32 {
+    const MULT: u64 = 6364136223846793005;
+
     pub fn new(init_state: u64, init_seq: u64) -> PCG32 {
         let mut rng = PCG32 { state: 0, inc: 0 };
         rng.seed(init_state, init_seq);
@@ -14,16 +16,31 @@ impl PCG32 {
         self.state = 0;
         self.inc = (init_seq << 1) | 1;
         self.u32();
-        self.state += init_state;
+        self.state = self.state.wrapping_add(init_state);
         self.u32();
     }
     pub fn u32(&mut self) -> u32 {
         let old_state = self.state;
-        self.state = old_state * 6364136223846793005 + self.inc;
+        self.state = old_state.wrapping_mul(PCG32::MULT).wrapping_add(self.inc);
         let xorshifted = (((old_state >> 18) ^ old_state) >> 27) as u32;
         let rot = (old_state >> 59) as u32;
         (xorshifted >> rot) | (xorshifted << (0u32.wrapping_sub(rot) & 31))
     }
+    // jump ahead by delta steps in O(log delta) (Brown 1994)
+    pub fn advance(&mut self, mut delta: u64) {
+        let (mut cur_mult, mut cur_plus) = (PCG32::MULT, self.inc);
+        let (mut acc_mult, mut acc_plus) = (1u64, 0u64);
+        while delta > 0 {
+            if delta & 1 == 1 {
+                acc_mult = acc_mult.wrapping_mul(cur_mult);
+                acc_plus = acc_plus.wrapping_mul(cur_mult).wrapping_add(cur_plus);
+            }
+            cur_plus = cur_mult.wrapping_add(1).wrapping_mul(cur_plus);
+            cur_mult = cur_mult.wrapping_mul(cur_mult);
+            delta >>= 1;
+        }
+        self.state = acc_mult.wrapping_mul(self.state).wrapping_add(acc_plus);
+    }
     pub fn u32_between(&mut self, lo: u32, hi: u32) -> u32 { lo + self.u32() % (hi - lo) } // not accurate
     pub fn f32(&mut self) -> f32 { (self.u32() >> 8) as f32 / (1 << 24) as f32 }
     pub fn f32_between(&mut self, lo: f32, hi: f32) -> f32 { lo + self.f32() * (hi - lo) }
@@ -0,0 +1,255 @@
+// Disney principled BSDF (Burley 2012, 2015). Isotropic GGX specular, GTR1 clearcoat, smooth dielectric
+// transmission, and the Hanrahan-Krueger inspired subsurface approximation blended into the diffuse lobe.
+
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use crate::hittable::{HitRecord, Ray};
+use crate::material::{reflect, refract, schlick_reflectance, Material};
+use crate::sampler::Sampler;
+use crate::vec3::Vec3;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PrincipledParams {
+    pub base_color: Vec3,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub specular: f32,
+    pub specular_tint: f32,
+    pub sheen: f32,
+    pub sheen_tint: f32,
+    pub clearcoat: f32,
+    pub clearcoat_gloss: f32,
+    pub transmission: f32,
+    pub ior: f32,
+    pub subsurface: f32,
+}
+
+impl Default for PrincipledParams {
+    fn default() -> PrincipledParams {
+        PrincipledParams {
+            base_color: Vec3(0.8, 0.8, 0.8),
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            specular_tint: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            transmission: 0.0,
+            ior: 1.5,
+            subsurface: 0.0,
+        }
+    }
+}
+
+// Parse whitespace separated `name=value` pairs, as found in exported asset data e.g.
+// "base_color=0.8,0.2,0.1 metallic=1 roughness=0.3". Missing parameters keep their default.
+impl FromStr for PrincipledParams {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PrincipledParams, String> {
+        let mut params = PrincipledParams::default();
+        for pair in s.split_whitespace() {
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or(format!("Expected name=value, found {pair}"))?;
+            let values = value
+                .split(',')
+                .map(|x| x.parse::<f32>().map_err(|e| format!("Invalid value for {name}: {e}")))
+                .collect::<Result<Vec<f32>, String>>()?;
+            let scalar = || match values[..] {
+                [x] => Ok(x),
+                _ => Err(format!("{name} expects 1 value")),
+            };
+            match name {
+                "base_color" => match values[..] {
+                    [r, g, b] => params.base_color = Vec3(r, g, b),
+                    _ => return Err(String::from("base_color expects 3 values")),
+                },
+                "metallic" => params.metallic = scalar()?,
+                "roughness" => params.roughness = scalar()?,
+                "specular" => params.specular = scalar()?,
+                "specular_tint" => params.specular_tint = scalar()?,
+                "sheen" => params.sheen = scalar()?,
+                "sheen_tint" => params.sheen_tint = scalar()?,
+                "clearcoat" => params.clearcoat = scalar()?,
+                "clearcoat_gloss" => params.clearcoat_gloss = scalar()?,
+                "transmission" => params.transmission = scalar()?,
+                "ior" => params.ior = scalar()?,
+                "subsurface" => params.subsurface = scalar()?,
+                _ => return Err(format!("Unknown parameter {name}")),
+            }
+        }
+        Ok(params)
+    }
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + t * (b - a)
+}
+fn schlick_weight(cos_theta: f32) -> f32 {
+    (1.0 - cos_theta).clamp(0.0, 1.0).powi(5)
+}
+fn ggx_d(cos_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let t = 1.0 + (a2 - 1.0) * cos_h * cos_h;
+    a2 / (PI * t * t)
+}
+fn gtr1_d(cos_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    (a2 - 1.0) / (PI * a2.ln() * (1.0 + (a2 - 1.0) * cos_h * cos_h))
+}
+fn smith_g1(cos_theta: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let cos2 = cos_theta * cos_theta;
+    2.0 * cos_theta / (cos_theta + (a2 + cos2 - a2 * cos2).sqrt())
+}
+// half vector around +z with the given cos(theta)
+fn half_vector(cos_h: f32, phi: f32) -> Vec3 {
+    let sin_h = (1.0 - cos_h * cos_h).max(0.0).sqrt();
+    Vec3(sin_h * phi.cos(), sin_h * phi.sin(), cos_h)
+}
+
+pub struct Principled {
+    params: PrincipledParams,
+    alpha: f32,
+    clearcoat_alpha: f32,
+    // lobe selection probabilities: diffuse, specular, clearcoat, transmission
+    lobe_probs: [f32; 4],
+}
+
+impl Principled {
+    pub fn new(params: PrincipledParams) -> Principled {
+        let p = &params;
+        let lobe_weights = [
+            (1.0 - p.metallic) * (1.0 - p.transmission),
+            1.0,
+            0.25 * p.clearcoat,
+            (1.0 - p.metallic) * p.transmission,
+        ];
+        let total: f32 = lobe_weights.iter().sum();
+        Principled {
+            params,
+            alpha: (p.roughness * p.roughness).max(1e-3),
+            clearcoat_alpha: lerp(0.1, 0.001, p.clearcoat_gloss),
+            lobe_probs: lobe_weights.map(|w| w / total),
+        }
+    }
+
+    fn tint(&self) -> Vec3 {
+        let luminance = self.params.base_color.luminance();
+        match luminance > 0.0 {
+            true => self.params.base_color / luminance,
+            false => Vec3::one(),
+        }
+    }
+
+    // BSDF value and solid angle pdf of the non-delta lobes, in the local frame where the normal is +z
+    fn eval_local(&self, wo: Vec3, wi: Vec3) -> (Vec3, f32) {
+        let p = &self.params;
+        let (cos_v, cos_l) = (wo.2, wi.2);
+        if cos_v <= 0.0 || cos_l <= 0.0 {
+            return (Vec3::zero(), 0.0);
+        }
+        let h = (wo + wi).normalize();
+        let cos_d = wi.dot(h);
+        let tint = self.tint();
+
+        // diffuse, retro-reflection and subsurface approximation
+        let (fl, fv) = (schlick_weight(cos_l), schlick_weight(cos_v));
+        let fd90 = 0.5 + 2.0 * cos_d * cos_d * p.roughness;
+        let fd = (1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv);
+        let fss90 = cos_d * cos_d * p.roughness;
+        let fss = (1.0 + (fss90 - 1.0) * fl) * (1.0 + (fss90 - 1.0) * fv);
+        let ss = 1.25 * (fss * (1.0 / (cos_l + cos_v) - 0.5) + 0.5);
+        let sheen = p.sheen * ((1.0 - p.sheen_tint) + p.sheen_tint * tint) * schlick_weight(cos_d);
+        let diffuse =
+            (p.base_color / PI * lerp(fd, ss, p.subsurface) + sheen) * ((1.0 - p.metallic) * (1.0 - p.transmission));
+
+        // specular
+        let specular_color = p.specular * 0.08 * ((1.0 - p.specular_tint) + p.specular_tint * tint);
+        let f0 = specular_color * (1.0 - p.metallic) + p.base_color * p.metallic;
+        let fresnel = f0 + (1.0 - f0) * schlick_weight(cos_d);
+        let ds = ggx_d(h.2, self.alpha);
+        let gs = smith_g1(cos_l, self.alpha) * smith_g1(cos_v, self.alpha);
+        let specular = fresnel * ds * gs / (4.0 * cos_l * cos_v);
+
+        // clearcoat
+        let dc = gtr1_d(h.2, self.clearcoat_alpha);
+        let fc = lerp(0.04, 1.0, schlick_weight(cos_d));
+        let gc = smith_g1(cos_l, 0.25) * smith_g1(cos_v, 0.25);
+        let clearcoat = 0.25 * p.clearcoat * dc * fc * gc / (4.0 * cos_l * cos_v);
+
+        let [p_diffuse, p_specular, p_clearcoat, _] = self.lobe_probs;
+        let half_pdf = h.2 / (4.0 * wo.dot(h));
+        let pdf = (p_diffuse * cos_l / PI + p_specular * ds * half_pdf + p_clearcoat * dc * half_pdf)
+            / (p_diffuse + p_specular + p_clearcoat);
+        (diffuse + specular + clearcoat, pdf)
+    }
+}
+
+impl Material for Principled {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<(Vec3, Vec3)> {
+        let incident = ray.direction.normalize();
+        let [p_diffuse, p_specular, p_clearcoat, p_transmission] = self.lobe_probs;
+        let u = sampler.get_1d();
+
+        if u < p_transmission {
+            let eta = match rec.front_face {
+                true => 1.0 / self.params.ior,
+                false => self.params.ior,
+            };
+            let cos_theta = (-incident.dot(rec.normal)).min(1.0);
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            let direction = match (eta * sin_theta <= 1.0) && (schlick_reflectance(cos_theta, eta) < sampler.get_1d()) {
+                true => refract(incident, rec.normal, eta),
+                false => reflect(incident, rec.normal),
+            };
+            let weight = (1.0 - self.params.metallic) * self.params.transmission / p_transmission;
+            return Some((direction, self.params.base_color * weight));
+        }
+
+        let (tangent, bitangent) = rec.normal.onb();
+        let to_local = |v: Vec3| Vec3(v.dot(tangent), v.dot(bitangent), v.dot(rec.normal));
+        let wo = to_local(-incident);
+
+        // pick one of the remaining lobes, reusing u
+        let u = (u - p_transmission) / (1.0 - p_transmission) * (p_diffuse + p_specular + p_clearcoat);
+        let (u1, u2) = sampler.get_2d();
+        let phi = 2.0 * PI * u2;
+        let wi = if u < p_diffuse {
+            let r = u1.sqrt();
+            Vec3(r * phi.cos(), r * phi.sin(), (1.0 - u1).sqrt())
+        } else {
+            let a2 = match u < p_diffuse + p_specular {
+                true => self.alpha * self.alpha,
+                false => self.clearcoat_alpha * self.clearcoat_alpha,
+            };
+            let cos_h = match u < p_diffuse + p_specular {
+                true => ((1.0 - u1) / (1.0 + (a2 - 1.0) * u1)).sqrt(),
+                false => ((1.0 - a2.powf(1.0 - u1)) / (1.0 - a2)).sqrt(),
+            };
+            reflect(-wo, half_vector(cos_h, phi))
+        };
+
+        let (f, pdf) = self.eval_local(wo, wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+        let direction = tangent * wi.0 + bitangent * wi.1 + rec.normal * wi.2;
+        Some((direction, f * wi.2 / (pdf * (1.0 - p_transmission))))
+    }
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        self.params.base_color
+    }
+    // the non-delta lobes only, light sampling can not find the transmission direction
+    fn eval(&self, ray: &Ray, rec: &HitRecord, wi: Vec3) -> Option<(Vec3, f32)> {
+        let (tangent, bitangent) = rec.normal.onb();
+        let to_local = |v: Vec3| Vec3(v.dot(tangent), v.dot(bitangent), v.dot(rec.normal));
+        let wi = to_local(wi);
+        let (f, pdf) = self.eval_local(to_local(-ray.direction.normalize()), wi);
+        Some((f * wi.2, pdf * (1.0 - self.lobe_probs[3])))
+    }
+}
@@ -0,0 +1,483 @@
+// Samplers hand out the random numbers of a pixel sample one dimension at a time. Every sample is deterministic
+// given its pixel and sample index, regardless of the order pixels and samples are rendered in.
+
+use std::sync::OnceLock;
+
+use crate::pcg32::PCG32;
+
+pub trait Sampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32);
+    fn get_1d(&mut self) -> f32;
+    fn get_2d(&mut self) -> (f32, f32);
+}
+
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+// finaliser of MurmurHash3
+fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5d329728ea185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81dadef4bc2dd44d);
+    v ^= v >> 33;
+    v
+}
+fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x2545f4914f6cdd1d, |h, &v| mix_bits(h ^ v))
+}
+
+// element i of a random permutation of 0..l selected by p, without storing the permutation (Kensler 2013)
+fn permutation_element(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            return (i.wrapping_add(p)) % l;
+        }
+    }
+}
+
+// Each sample owns a block of the pixel's PCG32 stream, so samples can be generated in any order
+pub struct IndependentSampler {
+    rng: PCG32,
+}
+impl IndependentSampler {
+    const SAMPLE_STRIDE: u64 = 65536;
+
+    pub fn new() -> IndependentSampler {
+        IndependentSampler { rng: PCG32::new(0, 0) }
+    }
+}
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.rng.seed(17 + pixel.1 as u64, 23 + pixel.0 as u64);
+        self.rng.advance(index as u64 * IndependentSampler::SAMPLE_STRIDE);
+    }
+    fn get_1d(&mut self) -> f32 {
+        self.rng.f32()
+    }
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.rng.f32(), self.rng.f32())
+    }
+}
+
+// Jittered stratification of every dimension into samples_per_pixel strata. The strata of different dimensions are
+// decorrelated with per-pixel random permutations.
+pub struct StratifiedSampler {
+    x_strata: u32,
+    y_strata: u32,
+    pixel: (u32, u32),
+    index: u32,
+    dimension: u32,
+    rng: IndependentSampler,
+}
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: u32) -> StratifiedSampler {
+        let x_strata = (samples_per_pixel as f32).sqrt().ceil() as u32;
+        let y_strata = samples_per_pixel.div_ceil(x_strata);
+        StratifiedSampler {
+            x_strata,
+            y_strata,
+            pixel: (0, 0),
+            index: 0,
+            dimension: 0,
+            rng: IndependentSampler::new(),
+        }
+    }
+    fn permuted_stratum(&mut self, count: u32) -> u32 {
+        let seed = hash(&[self.pixel.0 as u64, self.pixel.1 as u64, self.dimension as u64]) as u32;
+        self.dimension += 1;
+        permutation_element(self.index % count, count, seed)
+    }
+}
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        (self.pixel, self.index, self.dimension) = (pixel, index, 0);
+        self.rng.start_pixel_sample(pixel, index);
+    }
+    fn get_1d(&mut self) -> f32 {
+        let count = self.x_strata * self.y_strata;
+        let stratum = self.permuted_stratum(count);
+        ((stratum as f32 + self.rng.get_1d()) / count as f32).min(ONE_MINUS_EPSILON)
+    }
+    fn get_2d(&mut self) -> (f32, f32) {
+        let stratum = self.permuted_stratum(self.x_strata * self.y_strata);
+        let (x, y) = (stratum % self.x_strata, stratum / self.x_strata);
+        let (dx, dy) = self.rng.get_2d();
+        (
+            ((x as f32 + dx) / self.x_strata as f32).min(ONE_MINUS_EPSILON),
+            ((y as f32 + dy) / self.y_strata as f32).min(ONE_MINUS_EPSILON),
+        )
+    }
+}
+
+fn primes() -> &'static [u32] {
+    static PRIMES: OnceLock<Vec<u32>> = OnceLock::new();
+    PRIMES.get_or_init(|| {
+        let mut primes = Vec::new();
+        let mut n = 2;
+        while primes.len() < HaltonSampler::MAX_DIMENSIONS {
+            if primes.iter().all(|p| n % p != 0) {
+                primes.push(n);
+            }
+            n += 1;
+        }
+        primes
+    })
+}
+
+// Halton sequence with one prime base per dimension, Owen scrambled per pixel so that pixels are decorrelated
+pub struct HaltonSampler {
+    pixel: (u32, u32),
+    index: u32,
+    dimension: u32,
+    rng: IndependentSampler,
+}
+impl HaltonSampler {
+    const MAX_DIMENSIONS: usize = 256;
+
+    pub fn new() -> HaltonSampler {
+        HaltonSampler { pixel: (0, 0), index: 0, dimension: 0, rng: IndependentSampler::new() }
+    }
+    // radical inverse of a with its digits randomly permuted, each permutation depending on the previous digits
+    fn owen_scrambled_radical_inverse(base: u32, mut a: u64, seed: u32) -> f32 {
+        let inv_base = 1.0 / base as f64;
+        let (mut inv_base_m, mut reversed_digits) = (1.0_f64, 0_u64);
+        // keep going past the last digit of a, as zero digits are scrambled too
+        while 1.0 - (base - 1) as f64 * inv_base_m < 1.0 && inv_base_m > 1e-9 {
+            let next = a / base as u64;
+            let digit = (a - next * base as u64) as u32;
+            let digit_hash = mix_bits(seed as u64 ^ reversed_digits) as u32;
+            reversed_digits = reversed_digits * base as u64 + permutation_element(digit, base, digit_hash) as u64;
+            inv_base_m *= inv_base;
+            a = next;
+        }
+        ((inv_base_m * reversed_digits as f64) as f32).min(ONE_MINUS_EPSILON)
+    }
+    fn sample_dimension(&mut self) -> f32 {
+        let dimension = self.dimension as usize;
+        self.dimension += 1;
+        if dimension >= HaltonSampler::MAX_DIMENSIONS {
+            return self.rng.get_1d();
+        }
+        let seed = hash(&[self.pixel.0 as u64, self.pixel.1 as u64, dimension as u64]) as u32;
+        HaltonSampler::owen_scrambled_radical_inverse(primes()[dimension], self.index as u64, seed)
+    }
+}
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        (self.pixel, self.index, self.dimension) = (pixel, index, 0);
+        self.rng.start_pixel_sample(pixel, index);
+    }
+    fn get_1d(&mut self) -> f32 {
+        self.sample_dimension()
+    }
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.sample_dimension(), self.sample_dimension())
+    }
+}
+
+// first 2 dimensions of the Sobol sequence
+fn sobol_2d(index: u32) -> (u32, u32) {
+    let (mut x, mut y) = (0, 0);
+    let mut v = 1 << 31;
+    for bit in 0..32 {
+        if (index >> bit) & 1 == 1 {
+            x ^= 1 << (31 - bit);
+            y ^= v;
+        }
+        v ^= v >> 1;
+    }
+    (x, y)
+}
+// hash based nested uniform (Owen) scrambling of a 32 bit fixed point number (Burley 2020)
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x.reverse_bits()
+}
+fn to_f32(x: u32) -> f32 {
+    (x >> 8) as f32 / (1 << 24) as f32
+}
+
+// Owen scrambled 2D Sobol points, padded to higher dimensions by shuffling the sample index per dimension pair
+pub struct SobolSampler {
+    pixel: (u32, u32),
+    index: u32,
+    dimension: u32,
+}
+impl SobolSampler {
+    pub fn new() -> SobolSampler {
+        SobolSampler { pixel: (0, 0), index: 0, dimension: 0 }
+    }
+}
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        (self.pixel, self.index, self.dimension) = (pixel, index, 0);
+    }
+    fn get_1d(&mut self) -> f32 {
+        self.get_2d().0
+    }
+    fn get_2d(&mut self) -> (f32, f32) {
+        let seed = hash(&[self.pixel.0 as u64, self.pixel.1 as u64, self.dimension as u64]);
+        self.dimension += 1;
+        let index = nested_uniform_scramble(self.index, seed as u32);
+        let (x, y) = sobol_2d(index);
+        (
+            to_f32(nested_uniform_scramble(x, (seed >> 32) as u32)),
+            to_f32(nested_uniform_scramble(y, mix_bits(seed) as u32)),
+        )
+    }
+}
+
+// Blue noise dither mask generated with the void-and-cluster method (Ulichney 1993). Values are the ranks in [0, 1).
+fn blue_noise_mask() -> &'static [f32] {
+    static MASK: OnceLock<Vec<f32>> = OnceLock::new();
+    MASK.get_or_init(|| {
+        let size = BlueNoiseSampler::MASK_SIZE as i32;
+        let n = (size * size) as usize;
+        let sigma = 1.5_f32;
+        let kernel: Vec<f32> = (0..n as i32)
+            .map(|k| {
+                let (dx, dy) = (k % size, k / size);
+                let (dx, dy) = (dx.min(size - dx), dy.min(size - dy));
+                (-((dx * dx + dy * dy) as f32) / (2.0 * sigma * sigma)).exp()
+            })
+            .collect();
+        let mut energy = vec![0.0_f32; n];
+        let mut on = vec![false; n];
+        let update = |energy: &mut Vec<f32>, p: usize, sign: f32| {
+            let (px, py) = (p as i32 % size, p as i32 / size);
+            for (q, e) in energy.iter_mut().enumerate() {
+                let (dx, dy) = (
+                    (q as i32 % size - px).rem_euclid(size),
+                    (q as i32 / size - py).rem_euclid(size),
+                );
+                *e += sign * kernel[(dy * size + dx) as usize];
+            }
+        };
+        let tightest_cluster = |energy: &[f32], on: &[bool]| {
+            (0..n)
+                .filter(|&p| on[p])
+                .max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+                .unwrap()
+        };
+        let largest_void = |energy: &[f32], on: &[bool]| {
+            (0..n)
+                .filter(|&p| !on[p])
+                .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+                .unwrap()
+        };
+
+        // initial pattern: 10% random points, relaxed by moving the tightest cluster to the largest void
+        let mut rng = PCG32::new(1, 2);
+        let n_initial = n / 10;
+        let mut n_on = 0;
+        while n_on < n_initial {
+            let p = rng.u32_between(0, n as u32) as usize;
+            if !on[p] {
+                on[p] = true;
+                update(&mut energy, p, 1.0);
+                n_on += 1;
+            }
+        }
+        loop {
+            let cluster = tightest_cluster(&energy, &on);
+            on[cluster] = false;
+            update(&mut energy, cluster, -1.0);
+            let void = largest_void(&energy, &on);
+            on[void] = true;
+            update(&mut energy, void, 1.0);
+            if void == cluster {
+                break;
+            }
+        }
+
+        // rank the initial points by removing clusters, then fill the remaining voids
+        let mut rank = vec![0_usize; n];
+        let (mut removed_energy, mut removed_on) = (energy.clone(), on.clone());
+        for r in (0..n_initial).rev() {
+            let cluster = tightest_cluster(&removed_energy, &removed_on);
+            removed_on[cluster] = false;
+            update(&mut removed_energy, cluster, -1.0);
+            rank[cluster] = r;
+        }
+        for r in n_initial..n {
+            let void = largest_void(&energy, &on);
+            on[void] = true;
+            update(&mut energy, void, 1.0);
+            rank[void] = r;
+        }
+        rank.iter().map(|&r| r as f32 / n as f32).collect()
+    })
+}
+
+// Sobol points shared by all pixels, rotated per pixel by a blue noise mask (Georgiev and Fajardo 2016). The error
+// is distributed as blue noise across the image, which looks less noisy at low sample counts.
+pub struct BlueNoiseSampler {
+    pixel: (u32, u32),
+    index: u32,
+    dimension: u32,
+}
+impl BlueNoiseSampler {
+    const MASK_SIZE: u32 = 64;
+
+    pub fn new() -> BlueNoiseSampler {
+        blue_noise_mask();
+        BlueNoiseSampler { pixel: (0, 0), index: 0, dimension: 0 }
+    }
+    fn mask(&self, offset: u64) -> f32 {
+        let size = BlueNoiseSampler::MASK_SIZE;
+        let x = (self.pixel.0 + (offset as u32 % size)) % size;
+        let y = (self.pixel.1 + ((offset >> 32) as u32 % size)) % size;
+        blue_noise_mask()[(y * size + x) as usize]
+    }
+}
+impl Sampler for BlueNoiseSampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        (self.pixel, self.index, self.dimension) = (pixel, index, 0);
+    }
+    fn get_1d(&mut self) -> f32 {
+        self.get_2d().0
+    }
+    fn get_2d(&mut self) -> (f32, f32) {
+        let seed = hash(&[self.dimension as u64]);
+        self.dimension += 1;
+        let (x, y) = sobol_2d(nested_uniform_scramble(self.index, seed as u32));
+        let x = to_f32(nested_uniform_scramble(x, (seed >> 32) as u32)) + self.mask(mix_bits(seed));
+        let y = to_f32(nested_uniform_scramble(y, mix_bits(seed) as u32)) + self.mask(mix_bits(seed + 1));
+        (x.fract().min(ONE_MINUS_EPSILON), y.fract().min(ONE_MINUS_EPSILON))
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct PrimarySample {
+    value: f32,
+    modified: u64, // iteration of the last mutation
+    backup: (f32, u64),
+}
+
+// The primary sample vector of a Markov chain for Metropolis light transport (Kelemen et al. 2002). Values are
+// created the first time a dimension is asked for, and catch up with the mutations they missed when they are next
+// used. A large step replaces every value with a new random number, a small step perturbs each by a normal
+// distribution. A rejected proposal restores the previous values. The random numbers come from a PCG32 stream, so a
+// sampler created for the same stream replays the same samples. Dimensions are interleaved into independent streams
+// as well, so that e.g. a camera using more dimensions does not shift the dimensions of the path.
+pub struct MltSampler {
+    rng: PCG32,
+    sigma: f32,
+    large_step_probability: f32,
+    samples: Vec<PrimarySample>,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    stream: usize,
+    dimension: usize,
+}
+impl MltSampler {
+    pub const STREAMS: usize = 2;
+
+    pub fn new(stream: u64, sigma: f32, large_step_probability: f32) -> MltSampler {
+        MltSampler {
+            rng: PCG32::new(0, stream),
+            sigma,
+            large_step_probability,
+            samples: Vec::new(),
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            stream: 0,
+            dimension: 0,
+        }
+    }
+    // continues mutating with the random numbers of another stream, e.g. after replaying a sample
+    pub fn continue_on(&mut self, stream: u64) {
+        self.rng = PCG32::new(1, stream);
+    }
+    pub fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.f32() < self.large_step_probability;
+    }
+    pub fn start_stream(&mut self, stream: usize) {
+        (self.stream, self.dimension) = (stream, 0);
+    }
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+    pub fn reject(&mut self) {
+        for sample in self
+            .samples
+            .iter_mut()
+            .filter(|sample| sample.modified == self.iteration)
+        {
+            (sample.value, sample.modified) = sample.backup;
+        }
+        self.iteration -= 1;
+    }
+
+    fn mutate(&mut self, index: usize) {
+        if index >= self.samples.len() {
+            self.samples.resize(index + 1, PrimarySample::default());
+        }
+        let sample = &mut self.samples[index];
+        if sample.modified < self.last_large_step {
+            (sample.value, sample.modified) = (self.rng.f32(), self.last_large_step);
+        }
+        sample.backup = (sample.value, sample.modified);
+        match self.large_step {
+            true => sample.value = self.rng.f32(),
+            false => {
+                // the small steps missed since the last use add up to one with sqrt(n) times the deviation
+                let missed = (self.iteration - sample.modified) as f32;
+                let (u1, u2) = (1.0 - self.rng.f32(), self.rng.f32());
+                let normal = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos();
+                let value = sample.value + normal * self.sigma * missed.sqrt();
+                sample.value = (value - value.floor()).min(ONE_MINUS_EPSILON);
+            }
+        }
+        sample.modified = self.iteration;
+    }
+}
+impl Sampler for MltSampler {
+    // chains are not tied to pixels, a new sample starts over at the first dimension of the first stream
+    fn start_pixel_sample(&mut self, _pixel: (u32, u32), _index: u32) {
+        self.start_stream(0);
+    }
+    fn get_1d(&mut self) -> f32 {
+        let index = self.stream + self.dimension * MltSampler::STREAMS;
+        self.dimension += 1;
+        self.mutate(index);
+        self.samples[index].value
+    }
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.get_1d(), self.get_1d())
+    }
+}
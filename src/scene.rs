@@ -0,0 +1,226 @@
+// Scene to render: the objects, the lights which integrators can sample directly, and the medium the camera is in
+
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+use crate::hittable::{Hittable, HittableList, Ray, SceneIds, Sphere};
+use crate::material::DiffuseLight;
+use crate::medium::Medium;
+use crate::sampler::Sampler;
+use crate::vec3::Vec3;
+
+pub enum Light {
+    Sphere { center: Vec3, radius: f32, emission: Vec3 },
+    Sky { scale: f32 }, // white to blue gradient background
+}
+
+// light arriving at a point from a sampled direction
+pub struct LightSample {
+    pub direction: Vec3, // unit length
+    pub distance: f32,
+    pub radiance: Vec3, // RGB
+    pub pdf: f32,       // solid angle
+}
+
+// light leaving a light, for tracing paths from it
+pub struct EmissionSample {
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub direction: Vec3,
+    pub radiance: Vec3,
+    pub pdf_position: f32,  // area
+    pub pdf_direction: f32, // solid angle
+}
+
+impl Light {
+    // the sphere is added to the objects too, so that it is seen and blocks light
+    pub fn sphere(objects: &mut HittableList, center: Vec3, radius: f32, emission: Vec3) -> Light {
+        objects.push(Rc::new(Sphere::new(
+            center,
+            radius,
+            Rc::new(DiffuseLight::new(emission)),
+        )));
+        Light::Sphere { center, radius, emission }
+    }
+
+    fn sky(scale: f32, direction: Vec3) -> Vec3 {
+        let t = 0.5 * (direction.normalize().1 + 1.0);
+        let (color1, color2) = (Vec3::one(), Vec3(0.5, 0.7, 1.0));
+        (color1 + t * (color2 - color1)) * scale
+    }
+
+    // cosine of the half angle of the cone of directions from p to the sphere, None from inside the sphere
+    fn cone(p: Vec3, center: Vec3, radius: f32) -> Option<f32> {
+        let sin2_max = radius * radius / (center - p).length2();
+        match sin2_max < 1.0 {
+            true => Some((1.0 - sin2_max).sqrt()),
+            false => None,
+        }
+    }
+
+    // spheres are sampled uniformly within the cone they subtend, the sky uniformly over all directions
+    pub fn sample(&self, p: Vec3, sampler: &mut dyn Sampler) -> Option<LightSample> {
+        let (u1, u2) = sampler.get_2d();
+        match *self {
+            Light::Sphere { center, radius, emission } => {
+                let cos_max = Light::cone(p, center, radius)?;
+                let cos_theta = 1.0 - u1 * (1.0 - cos_max);
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * u2;
+                let axis = (center - p).normalize();
+                let (tangent, bitangent) = axis.onb();
+                let direction =
+                    tangent * (sin_theta * phi.cos()) + bitangent * (sin_theta * phi.sin()) + axis * cos_theta;
+
+                let d = (center - p).length();
+                let distance = d * cos_theta - (radius * radius - d * d * sin_theta * sin_theta).max(0.0).sqrt();
+                let pdf = 1.0 / (2.0 * PI * (1.0 - cos_max));
+                Some(LightSample { direction, distance, radiance: emission, pdf })
+            }
+            Light::Sky { scale } => {
+                let z = 1.0 - 2.0 * u1;
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                let direction = Vec3(r * (2.0 * PI * u2).cos(), r * (2.0 * PI * u2).sin(), z);
+                let radiance = Light::sky(scale, direction);
+                Some(LightSample { direction, distance: f32::INFINITY, radiance, pdf: 1.0 / (4.0 * PI) })
+            }
+        }
+    }
+
+    // Spheres emit from a uniform point on their surface in a cosine distributed direction. The sky can not be sampled
+    // this way, as it has no position.
+    pub fn sample_emission(&self, sampler: &mut dyn Sampler) -> Option<EmissionSample> {
+        let Light::Sphere { center, radius, emission } = *self else {
+            return None;
+        };
+        let (u1, u2) = sampler.get_2d();
+        let z = 1.0 - 2.0 * u1;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let normal = Vec3(r * (2.0 * PI * u2).cos(), r * (2.0 * PI * u2).sin(), z);
+
+        let (u1, u2) = sampler.get_2d();
+        let (tangent, bitangent) = normal.onb();
+        let (r, phi) = (u1.sqrt(), 2.0 * PI * u2);
+        let cos_theta = (1.0 - u1).sqrt();
+        let direction = tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normal * cos_theta;
+        Some(EmissionSample {
+            point: center + normal * radius,
+            normal,
+            direction,
+            radiance: emission,
+            pdf_position: 1.0 / (4.0 * PI * radius * radius),
+            pdf_direction: cos_theta / PI,
+        })
+    }
+    // The sky emits parallel light in a uniformly sampled direction, from a disk as wide as the sphere (center, radius)
+    // bounding the scene, just outside it
+    pub fn sample_sky_emission(
+        &self,
+        (center, radius): (Vec3, f32),
+        sampler: &mut dyn Sampler,
+    ) -> Option<EmissionSample> {
+        let Light::Sky { scale } = *self else {
+            return None;
+        };
+        let (u1, u2) = sampler.get_2d();
+        let z = 1.0 - 2.0 * u1;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let towards = Vec3(r * (2.0 * PI * u2).cos(), r * (2.0 * PI * u2).sin(), z);
+
+        let (u1, u2) = sampler.get_2d();
+        let (tangent, bitangent) = towards.onb();
+        let (r, phi) = (radius * u1.sqrt(), 2.0 * PI * u2);
+        Some(EmissionSample {
+            point: center + towards * radius + tangent * (r * phi.cos()) + bitangent * (r * phi.sin()),
+            normal: -towards,
+            direction: -towards,
+            radiance: Light::sky(scale, towards),
+            pdf_position: 1.0 / (PI * radius * radius),
+            pdf_direction: 1.0 / (4.0 * PI),
+        })
+    }
+    // solid angle pdf of sample_emission returning direction from the point with the given normal
+    pub fn emission_pdf(&self, normal: Vec3, direction: Vec3) -> f32 {
+        match self {
+            Light::Sphere { .. } => direction.normalize().dot(normal).max(0.0) / PI,
+            Light::Sky { .. } => 0.0,
+        }
+    }
+
+    // solid angle pdf of sample returning direction from p
+    fn pdf(&self, p: Vec3, direction: Vec3) -> f32 {
+        match *self {
+            Light::Sphere { center, radius, .. } => match Light::cone(p, center, radius) {
+                Some(cos_max) if direction.normalize().dot((center - p).normalize()) >= cos_max => {
+                    1.0 / (2.0 * PI * (1.0 - cos_max))
+                }
+                _ => 0.0,
+            },
+            Light::Sky { .. } => 1.0 / (4.0 * PI),
+        }
+    }
+}
+
+pub struct Scene {
+    pub objects: HittableList,
+    pub lights: Vec<Light>,
+    pub medium: Option<Rc<Medium>>, // where the camera is, None for vacuum
+}
+
+impl Scene {
+    pub fn new(objects: HittableList, lights: Vec<Light>) -> Scene {
+        objects.assign_ids(&mut SceneIds::default());
+        Scene { objects, lights, medium: None }
+    }
+
+    // RGB radiance of rays leaving the scene
+    pub fn background(&self, ray: &Ray) -> Vec3 {
+        self.lights
+            .iter()
+            .map(|light| match light {
+                Light::Sky { scale } => Light::sky(*scale, ray.direction),
+                _ => Vec3::zero(),
+            })
+            .fold(Vec3::zero(), |sum, radiance| sum + radiance)
+    }
+
+    // one light chosen uniformly, and the probability of choosing it
+    pub fn sample_light(&self, sampler: &mut dyn Sampler) -> Option<(&Light, f32)> {
+        let n = self.lights.len();
+        let index = ((sampler.get_1d() * n as f32) as usize).min(n.checked_sub(1)?);
+        Some((&self.lights[index], 1.0 / n as f32))
+    }
+
+    // the light emitting from point p, or the sky for None
+    pub fn emitter(&self, p: Option<Vec3>) -> Option<&Light> {
+        self.lights.iter().find(|light| match (light, p) {
+            (Light::Sky { .. }, None) => true,
+            (Light::Sphere { center, radius, .. }, Some(p)) => ((p - *center).length() - radius).abs() < 1e-3 * radius,
+            _ => false,
+        })
+    }
+
+    // Probability density of sample_light and Light::sample producing the ray from p to the emitter at point `to`,
+    // or to the sky for None
+    pub fn light_pdf(&self, p: Vec3, direction: Vec3, to: Option<Vec3>) -> f32 {
+        match self.emitter(to) {
+            Some(light) => light.pdf(p, direction) / self.lights.len() as f32,
+            None => 0.0,
+        }
+    }
+
+    // Density of sample_light and Light::sample_emission starting a path at p, 0 where no light can: per area on
+    // spheres. For the sky, None, it is the solid angle density of Light::sample_sky_emission's direction.
+    pub fn origin_pdf(&self, p: Option<Vec3>) -> f32 {
+        match self.emitter(p) {
+            Some(Light::Sphere { radius, .. }) => 1.0 / (4.0 * PI * radius * radius * self.lights.len() as f32),
+            Some(Light::Sky { .. }) => 1.0 / (4.0 * PI * self.lights.len() as f32),
+            None => 0.0,
+        }
+    }
+
+    // where light subpaths from the sky start, see Light::sample_sky_emission
+    pub fn bounding_sphere(&self) -> (Vec3, f32) {
+        self.objects.bbox().bounding_sphere()
+    }
+}
@@ -0,0 +1,104 @@
+// Hero-wavelength spectral sampling. A path carries 3 wavelengths, and spectral quantities along the path are
+// stored in a Vec3 where each component belongs to one wavelength instead of to an RGB channel.
+
+use std::sync::OnceLock;
+
+use crate::vec3::Vec3;
+
+pub const LAMBDA_MIN: f32 = 380.0;
+pub const LAMBDA_MAX: f32 = 720.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Wavelengths {
+    pub lambda: Vec3,
+    pdf: Vec3,
+}
+
+impl Wavelengths {
+    // hero wavelength is sampled uniformly, the other 2 are rotated by 1/3 of the visible range
+    pub fn sample(u: f32) -> Wavelengths {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let lambda = |offset: f32| LAMBDA_MIN + (u + offset).fract() * range;
+        Wavelengths {
+            lambda: Vec3(lambda(0.0), lambda(1.0 / 3.0), lambda(2.0 / 3.0)),
+            pdf: Vec3::one() / range,
+        }
+    }
+    pub fn hero(&self) -> f32 {
+        self.lambda.0
+    }
+    // used when the path direction depends on the wavelength e.g. dispersion. only the hero wavelength survives.
+    pub fn terminate_secondary(&mut self) {
+        if !self.secondary_terminated() {
+            self.pdf = Vec3(self.pdf.0 / 3.0, 0.0, 0.0);
+        }
+    }
+    pub fn secondary_terminated(&self) -> bool {
+        self.pdf.1 == 0.0
+    }
+    pub fn upsample(&self, rgb: Vec3) -> Vec3 {
+        Vec3(
+            rgb_to_spectrum(rgb, self.lambda.0),
+            rgb_to_spectrum(rgb, self.lambda.1),
+            rgb_to_spectrum(rgb, self.lambda.2),
+        )
+    }
+    pub fn to_rgb(self, radiance: Vec3) -> Vec3 {
+        let mut xyz = Vec3::zero();
+        for i in 0..3 {
+            if self.pdf[i] > 0.0 {
+                xyz = xyz + radiance[i] * cie_xyz(self.lambda[i]) / self.pdf[i];
+            }
+        }
+        xyz_to_linear_srgb(xyz / 3.0) / white_rgb()
+    }
+}
+
+fn smoothstep(lo: f32, hi: f32, x: f32) -> f32 {
+    let t = ((x - lo) / (hi - lo)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Smooth basis functions for blue, green and red which sum to 1 at every wavelength. White albedo becomes a flat
+// spectrum and albedo <= 1 stays <= 1, so energy conservation of the materials is preserved.
+fn rgb_to_spectrum(rgb: Vec3, lambda: f32) -> f32 {
+    let blue = 1.0 - smoothstep(470.0, 520.0, lambda);
+    let red = smoothstep(570.0, 610.0, lambda);
+    let green = 1.0 - blue - red;
+    rgb.0 * red + rgb.1 * green + rgb.2 * blue
+}
+
+// multi-lobe Gaussian fit of CIE 1931 colour matching functions (Wyman, Sloan and Shirley 2013)
+fn cie_xyz(lambda: f32) -> Vec3 {
+    let g = |mu: f32, sigma1: f32, sigma2: f32| {
+        let t = (lambda - mu) / if lambda < mu { sigma1 } else { sigma2 };
+        (-0.5 * t * t).exp()
+    };
+    Vec3(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+fn xyz_to_linear_srgb(xyz: Vec3) -> Vec3 {
+    Vec3(
+        3.2406 * xyz.0 - 1.5372 * xyz.1 - 0.4986 * xyz.2,
+        -0.9689 * xyz.0 + 1.8758 * xyz.1 + 0.0415 * xyz.2,
+        0.0557 * xyz.0 - 0.2040 * xyz.1 + 1.0570 * xyz.2,
+    )
+}
+
+// linear sRGB of a flat unit spectrum. dividing by it white balances the film so that white albedo stays white.
+fn white_rgb() -> Vec3 {
+    static WHITE: OnceLock<Vec3> = OnceLock::new();
+    *WHITE.get_or_init(|| {
+        let mut xyz = Vec3::zero();
+        let mut lambda = LAMBDA_MIN + 0.5;
+        while lambda < LAMBDA_MAX {
+            xyz = xyz + cie_xyz(lambda);
+            lambda += 1.0;
+        }
+        xyz_to_linear_srgb(xyz)
+    })
+}
@@ -0,0 +1,237 @@
+// Stochastic progressive photon mapping (Hachisuka and Jensen 2009), in the probabilistic formulation of Knaus and
+// Zwicker 2011. Every rendering pass shoots a new set of photons from the lights, and camera paths gather the photons
+// around the first diffuse or glossy surface they reach. The gather radius shrinks from pass to pass, so that the
+// blur and bias of the density estimate vanish while its variance grows slowly enough for the average over passes to
+// converge: the estimate is consistent. Specular paths from the lights to diffuse surfaces, such as caustics of a lamp
+// behind glass, are found as easily as any others.
+
+use std::cell::RefCell;
+use std::f32::consts::PI;
+
+use crate::camera::Camera;
+use crate::hittable::{Hittable, Ray, AABB};
+use crate::integrator::{emitted, is_specular, russian_roulette, sample_one_light, scatter_pdf, Integrator, Radiance};
+use crate::sampler::{IndependentSampler, Sampler};
+use crate::scene::{Light, Scene};
+use crate::vec3::Vec3;
+
+// radius reduction, the fraction of photons of each pass that is kept in the limit
+const ALPHA: f32 = 2.0 / 3.0;
+
+// Bounding sphere of where camera rays through a grid on the film start and what they first hit. Sky photons only need
+// to reach that region, which can be far smaller than the whole scene, e.g. with a huge sphere as the ground.
+fn visible_region(scene: &Scene, camera: &dyn Camera, sampler: &mut dyn Sampler) -> (Vec3, f32) {
+    const GRID: u32 = 64;
+    let mut points = Vec::new();
+    for index in 0..GRID * GRID {
+        let s = ((index % GRID) as f32 + 0.5) / GRID as f32;
+        let t = ((index / GRID) as f32 + 0.5) / GRID as f32;
+        let Some((ray, _)) = camera.get_ray(s, t, sampler) else {
+            continue;
+        };
+        points.push(ray.origin);
+        if let Some(rec) = scene.objects.hit(&ray, 0.001, f32::INFINITY) {
+            points.push(rec.p);
+        }
+    }
+    match points.is_empty() {
+        true => scene.objects.bbox().bounding_sphere(),
+        false => AABB::around(points).bounding_sphere(),
+    }
+}
+
+struct Photon {
+    p: Vec3,
+    incoming: Vec3, // unit direction the photon arrived in
+    power: Vec3,    // RGB flux
+}
+
+// Photons sorted into the cells of a uniform grid as wide as the gather radius, hashed into as many buckets as there
+// are photons. Photons within the radius of a point are in the 27 cells around it.
+struct PhotonMap {
+    radius: f32,
+    emitted: u32, // photons shot, including those that never reached a surface
+    photons: Vec<Photon>,
+    starts: Vec<usize>, // the photons of bucket b are photons[starts[b]..starts[b + 1]]
+}
+
+impl PhotonMap {
+    fn new(radius: f32, emitted: u32, photons: Vec<Photon>) -> PhotonMap {
+        let mut map = PhotonMap { radius, emitted, photons: Vec::new(), starts: vec![0; photons.len().max(1) + 1] };
+        let buckets: Vec<usize> = photons.iter().map(|photon| map.bucket(map.cell(photon.p))).collect();
+        for &bucket in &buckets {
+            map.starts[bucket + 1] += 1;
+        }
+        for b in 1..map.starts.len() {
+            map.starts[b] += map.starts[b - 1];
+        }
+        // counting sort by bucket
+        let mut next = map.starts.clone();
+        let mut slots: Vec<Option<Photon>> = photons.iter().map(|_| None).collect();
+        for (photon, bucket) in photons.into_iter().zip(buckets) {
+            slots[next[bucket]] = Some(photon);
+            next[bucket] += 1;
+        }
+        map.photons = slots.into_iter().flatten().collect();
+        map
+    }
+
+    fn cell(&self, p: Vec3) -> (i32, i32, i32) {
+        let cell = p / self.radius;
+        (cell.0.floor() as i32, cell.1.floor() as i32, cell.2.floor() as i32)
+    }
+
+    fn bucket(&self, (x, y, z): (i32, i32, i32)) -> usize {
+        let h =
+            (x as u32).wrapping_mul(73856093) ^ (y as u32).wrapping_mul(19349663) ^ (z as u32).wrapping_mul(83492791);
+        h as usize % (self.starts.len() - 1)
+    }
+
+    // calls f with every photon within the radius of p
+    fn gather(&self, p: Vec3, mut f: impl FnMut(&Photon)) {
+        let (x, y, z) = self.cell(p);
+        let mut buckets: Vec<usize> = (0..27)
+            .map(|n| self.bucket((x + n % 3 - 1, y + n / 3 % 3 - 1, z + n / 9 - 1)))
+            .collect();
+        // cells that share a bucket must not be counted twice
+        buckets.sort_unstable();
+        buckets.dedup();
+        let radius2 = self.radius * self.radius;
+        for bucket in buckets {
+            for photon in &self.photons[self.starts[bucket]..self.starts[bucket + 1]] {
+                if (photon.p - p).length2() < radius2 {
+                    f(photon);
+                }
+            }
+        }
+    }
+}
+
+pub struct SppmIntegrator {
+    max_depth: u32,
+    min_depth: u32,
+    photons_per_pass: u32,
+    initial_radius: f32,
+    photon_map: RefCell<PhotonMap>,
+}
+
+impl SppmIntegrator {
+    pub fn new(max_depth: u32, min_depth: u32, photons_per_pass: u32, initial_radius: f32) -> SppmIntegrator {
+        SppmIntegrator {
+            max_depth,
+            min_depth,
+            photons_per_pass,
+            initial_radius,
+            photon_map: RefCell::new(PhotonMap::new(initial_radius, 0, Vec::new())),
+        }
+    }
+
+    // r_{i+1}^2 = r_i^2 (i + alpha) / (i + 1)
+    fn radius(&self, pass: u32) -> f32 {
+        let radius2 = (1..pass).fold(self.initial_radius * self.initial_radius, |radius2, i| {
+            radius2 * (i as f32 + ALPHA) / (i + 1) as f32
+        });
+        radius2.sqrt()
+    }
+
+    // Follows a photon from a light, and stores it wherever it lands on a diffuse or glossy surface after at least
+    // one bounce. Light arriving directly is left to light sampling at the camera paths' ends. Sky photons start
+    // from a disk as wide as the region seen by the camera.
+    fn trace_photon(&self, scene: &Scene, region: (Vec3, f32), sampler: &mut dyn Sampler, photons: &mut Vec<Photon>) {
+        let Some((light, choice_pdf)) = scene.sample_light(sampler) else {
+            return;
+        };
+        let emission = match light {
+            Light::Sky { .. } => light.sample_sky_emission(region, sampler),
+            _ => light.sample_emission(sampler),
+        };
+        let Some(emission) = emission else {
+            return;
+        };
+        let cos_theta = emission.direction.dot(emission.normal);
+        let mut power = emission.radiance * (cos_theta / (emission.pdf_position * emission.pdf_direction * choice_pdf));
+        let mut ray = Ray::new(emission.point + emission.normal * 1e-4, emission.direction);
+
+        for bounce in 0..self.max_depth {
+            let Some(rec) = scene.objects.hit(&ray, 0.001, f32::INFINITY) else {
+                break;
+            };
+            if bounce > 0 && !is_specular(&ray, &rec) {
+                photons.push(Photon { p: rec.p, incoming: ray.direction.normalize(), power });
+            }
+            let Some((direction, color)) = rec.material.scatter(&ray, &rec, sampler) else {
+                break;
+            };
+            power = power * color;
+            if bounce + 1 >= self.min_depth && !russian_roulette(&mut power, sampler) {
+                break;
+            }
+            ray = ray.spawn(&rec, direction);
+        }
+    }
+}
+
+impl Integrator for SppmIntegrator {
+    // Camera paths follow specular bounces to the first diffuse or glossy surface, which is lit directly by light
+    // sampling and indirectly by the photons around it. Delta lobes of such surfaces are followed further.
+    fn li(&self, mut ray: Ray, scene: &Scene, _camera: &dyn Camera, sampler: &mut dyn Sampler) -> Radiance {
+        let mut radiance = Radiance::zero();
+        let mut throughput = Vec3::one();
+        for bounce in 0..self.max_depth {
+            let rec = scene.objects.hit(&ray, 0.001, f32::INFINITY);
+            radiance.add(bounce, throughput * emitted(scene, &ray, rec.as_ref()));
+            let Some(rec) = rec else {
+                break;
+            };
+            if !is_specular(&ray, &rec) {
+                radiance.add(
+                    bounce + 1,
+                    throughput * sample_one_light(scene, &ray, &rec, sampler, false),
+                );
+
+                let photon_map = self.photon_map.borrow();
+                let normal = rec.material.shading_normal(&rec);
+                let mut flux = Vec3::zero();
+                photon_map.gather(rec.p, |photon| {
+                    // the BSDF without the cosine, as the flux of the photon is already projected
+                    let wi = -photon.incoming;
+                    let cos = wi.dot(normal).abs();
+                    if let Some((f, _)) = rec.material.eval(&ray, &rec, wi).filter(|_| cos > 1e-4) {
+                        flux = flux + ray.spectrum(f / cos) * ray.spectrum(photon.power);
+                    }
+                });
+                let area = PI * photon_map.radius * photon_map.radius;
+                radiance.add(
+                    bounce + 2,
+                    throughput * flux / (area * photon_map.emitted.max(1) as f32),
+                );
+            }
+            let Some((direction, color)) = rec.material.scatter(&ray, &rec, sampler) else {
+                break;
+            };
+            // the photons stand in for everything but delta lobes
+            if scatter_pdf(&ray, &rec, direction).is_some() {
+                break;
+            }
+            throughput = throughput * ray.spectrum(color);
+            if bounce + 1 >= self.min_depth && !russian_roulette(&mut throughput, sampler) {
+                break;
+            }
+            ray = ray.spawn(&rec, direction);
+        }
+        radiance.end_path(&ray);
+        radiance
+    }
+
+    fn start_pass(&self, scene: &Scene, camera: &dyn Camera, pass: u32) {
+        let mut sampler = IndependentSampler::new();
+        sampler.start_pixel_sample((u32::MAX, pass), self.photons_per_pass);
+        let region = visible_region(scene, camera, &mut sampler);
+        let mut photons = Vec::new();
+        for index in 0..self.photons_per_pass {
+            sampler.start_pixel_sample((u32::MAX, pass), index);
+            self.trace_photon(scene, region, &mut sampler, &mut photons);
+        }
+        *self.photon_map.borrow_mut() = PhotonMap::new(self.radius(pass), self.photons_per_pass, photons);
+    }
+}
@@ -0,0 +1,38 @@
+use std::f32::consts::PI;
+
+use crate::image::Image;
+use crate::vec3::Vec3;
+
+pub trait Texture {
+    fn value(&self, uv: (f32, f32), p: Vec3) -> Vec3;
+}
+
+pub struct ImageTexture {
+    image: Image,
+}
+impl ImageTexture {
+    pub fn new(image: Image) -> ImageTexture {
+        ImageTexture { image }
+    }
+}
+impl Texture for ImageTexture {
+    fn value(&self, uv: (f32, f32), _p: Vec3) -> Vec3 {
+        self.image.get(uv.0, uv.1)
+    }
+}
+
+// product of sine waves along u and v, in [0, 1]. useful as a height field.
+pub struct Waves {
+    frequency: (f32, f32),
+}
+impl Waves {
+    pub fn new(frequency: (f32, f32)) -> Waves {
+        Waves { frequency }
+    }
+}
+impl Texture for Waves {
+    fn value(&self, uv: (f32, f32), _p: Vec3) -> Vec3 {
+        let h = (2.0 * PI * self.frequency.0 * uv.0).sin() * (2.0 * PI * self.frequency.1 * uv.1).sin();
+        Vec3::one() * (0.5 + 0.5 * h)
+    }
+}
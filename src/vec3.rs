@@ -1,4 +1,7 @@
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
 use crate::pcg32::PCG32;
+use crate::sampler::Sampler;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Vec3(pub f32, pub f32, pub f32);
@@ -18,6 +21,17 @@ impl Vec3 {
         )
     }
     pub fn normalize(self) -> Vec3 { self / self.length() }
+    pub fn luminance(self) -> f32 { 0.2126 * self.0 + 0.7152 * self.1 + 0.0722 * self.2 }
+    // tangent and bitangent completing a unit vector to an orthonormal basis (Duff et al. 2017)
+    pub fn onb(self) -> (Vec3, Vec3) {
+        let sign = 1.0_f32.copysign(self.2);
+        let a = -1.0 / (sign + self.2);
+        let b = self.0 * self.1 * a;
+        (
+            Vec3(1.0 + sign * self.0 * self.0 * a, sign * b, -sign * self.0),
+            Vec3(b, sign + self.1 * self.1 * a, -self.1),
+        )
+    }
     pub fn rand(rng: &mut PCG32) -> Vec3 { Vec3(rng.f32(), rng.f32(), rng.f32()) }
     pub fn rand_between(rng: &mut PCG32, lo: f32, hi: f32) -> Vec3 {
         Vec3(
@@ -26,21 +40,26 @@ impl Vec3 {
             rng.f32_between(lo, hi),
         )
     }
-    pub fn random_unit_sphere(rng: &mut PCG32) -> Vec3 {
-        loop {
-            let p = Vec3::rand_between(rng, -1.0, 1.0);
-            if p.length2() < 1.0 {
-                return p;
-            }
-        }
-    }
-    pub fn random_unit_disk(rng: &mut PCG32) -> Vec3 {
-        loop {
-            let p = Vec3(rng.f32(), rng.f32(), 0.0);
-            if p.length2() < 1.0 {
-                return p;
-            }
+    // uniform point in the unit ball: uniform direction scaled by the cube root of a uniform radius
+    pub fn random_unit_sphere(sampler: &mut dyn Sampler) -> Vec3 {
+        let (u1, u2) = sampler.get_2d();
+        let z = 1.0 - 2.0 * u1;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+        Vec3(r * phi.cos(), r * phi.sin(), z) * sampler.get_1d().cbrt()
+    }
+    // concentric mapping of the unit square to the unit disk (Shirley and Chiu 1997)
+    pub fn random_unit_disk(sampler: &mut dyn Sampler) -> Vec3 {
+        let (u1, u2) = sampler.get_2d();
+        let (a, b) = (2.0 * u1 - 1.0, 2.0 * u2 - 1.0);
+        if a == 0.0 && b == 0.0 {
+            return Vec3::zero();
         }
+        let (r, theta) = match a.abs() > b.abs() {
+            true => (a, FRAC_PI_4 * (b / a)),
+            false => (b, FRAC_PI_2 - FRAC_PI_4 * (a / b)),
+        };
+        Vec3(r * theta.cos(), r * theta.sin(), 0.0)
     }
 }
 